@@ -0,0 +1,91 @@
+//! Compares parsing NEP-171 logs the way the indexer used to, trying them as a
+//! mint, a transfer and a burn in turn, with parsing the envelope once and
+//! dispatching on `event`.
+//!
+//! Runs over recorded blocks, so that the mix of events is the one of mainnet.
+//! Capture a busy range with `cargo run --example capture_fixtures -- <start> <end>`
+//! and run with `NFT_BENCH_BLOCKS=<start>..<end>`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_utils::{EventLogData, NftBurnLog, NftMintLog, NftTransferLog};
+use nft_indexer::parse_event_log;
+use nft_indexer::recorded_blocks::{self, RecordedBlockProvider};
+
+/// Where `capture_fixtures` saves blocks by default
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/blocks");
+
+fn block_range() -> (BlockHeight, BlockHeight) {
+    let range = std::env::var("NFT_BENCH_BLOCKS").expect(
+        "Set NFT_BENCH_BLOCKS=<start>..<end> to a busy range captured with capture_fixtures",
+    );
+    let (start, end) = range
+        .split_once("..")
+        .expect("NFT_BENCH_BLOCKS should be <start>..<end>");
+    (
+        start.parse().expect("Invalid start block"),
+        end.parse().expect("Invalid end block"),
+    )
+}
+
+fn recorded_logs(start: BlockHeight, end: BlockHeight) -> Vec<String> {
+    let provider = RecordedBlockProvider::new(FIXTURES_DIR);
+    let mut logs = Vec::new();
+    for block_height in start..end {
+        let block = provider.load(block_height).unwrap().unwrap_or_else(|| {
+            panic!(
+                "Block {block_height} is missing from {}, capture it with `cargo run --example capture_fixtures -- {start} {end}`",
+                recorded_blocks::block_path(std::path::Path::new(FIXTURES_DIR), block_height)
+                    .display()
+            )
+        });
+        for shard in block.shards {
+            for outcome in shard.receipt_execution_outcomes {
+                logs.extend(
+                    outcome
+                        .execution_outcome
+                        .outcome
+                        .logs
+                        .into_iter()
+                        .filter(|log| log.contains("nep171")),
+                );
+            }
+        }
+    }
+    logs
+}
+
+fn parse_logs(c: &mut Criterion) {
+    let (start, end) = block_range();
+    let logs = recorded_logs(start, end);
+    assert!(!logs.is_empty(), "No NEP-171 logs in the recorded blocks");
+
+    let mut group = c.benchmark_group("parse_logs");
+    group.throughput(Throughput::Elements(logs.len() as u64));
+    group.bench_function("mint_transfer_burn", |b| {
+        b.iter(|| {
+            for log in &logs {
+                if let Ok(log) = EventLogData::<NftMintLog>::deserialize(log) {
+                    black_box(log.validate());
+                }
+                if let Ok(log) = EventLogData::<NftTransferLog>::deserialize(log) {
+                    black_box(log.validate());
+                }
+                if let Ok(log) = EventLogData::<NftBurnLog>::deserialize(log) {
+                    black_box(log.validate());
+                }
+            }
+        })
+    });
+    group.bench_function("envelope_once", |b| {
+        b.iter(|| {
+            for log in &logs {
+                black_box(parse_event_log(log));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse_logs);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+//! Saves blocks from neardata for `RecordedBlockProvider`.
+//!
+//! Usage: `cargo run --example capture_fixtures -- [start-block] [end-block] [dir]`,
+//! `dir` defaults to the fixtures of this crate's tests.
+
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::StreamerMessage;
+use inindexer::neardata::NeardataProvider;
+use inindexer::{run_indexer, BlockRange, Indexer, IndexerOptions};
+use nft_indexer::recorded_blocks::{self, RecordedBlockError};
+
+/// Default `dir`, next to this crate's tests
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/blocks");
+
+struct CaptureBlocks {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl Indexer for CaptureBlocks {
+    type Error = RecordedBlockError;
+
+    async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
+        log::info!("Captured block {}", block.block.header.height);
+        recorded_blocks::save_block(&self.dir, block.block.header.height, Some(block))
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .init()
+        .unwrap();
+
+    let msg = "Usage: `capture_fixtures [start-block] [end-block] [dir]`";
+    let start_inclusive = parse_block_height(std::env::args().nth(1).expect(msg), msg);
+    let end_exclusive = parse_block_height(std::env::args().nth(2).expect(msg), msg);
+    let dir = std::env::args()
+        .nth(3)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(FIXTURES_DIR));
+    std::fs::create_dir_all(&dir).expect("Failed to create fixtures directory");
+
+    let mut indexer = CaptureBlocks { dir: dir.clone() };
+    run_indexer(
+        &mut indexer,
+        NeardataProvider::mainnet(),
+        IndexerOptions::default_with_range(BlockRange::Range {
+            start_inclusive,
+            end_exclusive: Some(end_exclusive),
+        }),
+    )
+    .await
+    .expect("Failed to capture blocks");
+
+    // Skipped heights have no block, record them so that they aren't reported as missing
+    for block_height in start_inclusive..end_exclusive {
+        if !recorded_blocks::block_path(&dir, block_height).exists() {
+            recorded_blocks::save_block(&dir, block_height, None).expect("Failed to save block");
+        }
+    }
+    log::info!(
+        "Saved blocks {start_inclusive}..{end_exclusive} to {}",
+        dir.display()
+    );
+}
+
+fn parse_block_height(arg: String, msg: &str) -> u64 {
+    arg.replace(['_', ',', ' ', '.'], "").parse().expect(msg)
+}
@@ -0,0 +1,104 @@
+-- Amounts are in yoctoNEAR or the smallest unit of the fungible token, and don't fit in BIGINT
+-- Events are keyed by (receipt_id, log_index, event_index, token_id), so re-indexing a block is a no-op
+
+CREATE TABLE nft_mints (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    memo TEXT,
+    transaction_id TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    block_timestamp_nanosec BIGINT NOT NULL,
+    spam_score DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_mints_contract_token ON nft_mints (contract_id, token_id);
+CREATE INDEX nft_mints_owner ON nft_mints (owner_id);
+CREATE INDEX nft_mints_block_height ON nft_mints (block_height);
+
+CREATE TABLE nft_transfers (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    old_owner_id TEXT NOT NULL,
+    new_owner_id TEXT NOT NULL,
+    authorized_id TEXT,
+    memo TEXT,
+    reverted_transfer_receipt_id TEXT,
+    suspected_wash_trade BOOLEAN NOT NULL,
+    transaction_id TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    block_timestamp_nanosec BIGINT NOT NULL,
+    spam_score DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_transfers_contract_token ON nft_transfers (contract_id, token_id);
+CREATE INDEX nft_transfers_old_owner ON nft_transfers (old_owner_id);
+CREATE INDEX nft_transfers_new_owner ON nft_transfers (new_owner_id);
+CREATE INDEX nft_transfers_block_height ON nft_transfers (block_height);
+
+-- One row per traded token of a transfer
+CREATE TABLE nft_trades (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    price NUMERIC(39, 0) NOT NULL,
+    -- 'near', or the account ID of the fungible token contract
+    currency TEXT NOT NULL,
+    marketplace_id TEXT NOT NULL,
+    buyer_id TEXT NOT NULL,
+    approval_id BIGINT,
+    declared_balance NUMERIC(39, 0) NOT NULL,
+    max_len_payout BIGINT,
+    -- NULL if the payout is unknown
+    seller_proceeds NUMERIC(39, 0),
+    marketplace_fee NUMERIC(39, 0),
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id),
+    FOREIGN KEY (receipt_id, log_index, event_index, token_id)
+        REFERENCES nft_transfers (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_trades_marketplace ON nft_trades (marketplace_id);
+CREATE INDEX nft_trades_buyer ON nft_trades (buyer_id);
+
+CREATE TABLE nft_trade_royalties (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    amount NUMERIC(39, 0) NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id, account_id),
+    FOREIGN KEY (receipt_id, log_index, event_index, token_id)
+        REFERENCES nft_trades (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_trade_royalties_account ON nft_trade_royalties (account_id);
+
+CREATE TABLE nft_burns (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    authorized_id TEXT,
+    memo TEXT,
+    transaction_id TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    block_timestamp_nanosec BIGINT NOT NULL,
+    spam_score DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_burns_contract_token ON nft_burns (contract_id, token_id);
+CREATE INDEX nft_burns_block_height ON nft_burns (block_height);
+
+-- Last block written by each indexer, updated in the same transaction as its events
+CREATE TABLE nft_checkpoints (
+    name TEXT PRIMARY KEY,
+    block_height BIGINT NOT NULL
+);
@@ -0,0 +1,165 @@
+-- Amounts are stored as decimal strings, because they don't fit in SQLite integers
+-- Events are keyed by (receipt_id, log_index, event_index, token_id), like in migrations/postgres
+
+CREATE TABLE nft_mints (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    memo TEXT,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    spam_score REAL NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_mints_contract_token ON nft_mints (contract_id, token_id);
+CREATE INDEX nft_mints_block_height ON nft_mints (block_height);
+
+CREATE TABLE nft_transfers (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    old_owner_id TEXT NOT NULL,
+    new_owner_id TEXT NOT NULL,
+    authorized_id TEXT,
+    memo TEXT,
+    reverted_transfer_receipt_id TEXT,
+    suspected_wash_trade INTEGER NOT NULL,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    spam_score REAL NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_transfers_contract_token ON nft_transfers (contract_id, token_id);
+CREATE INDEX nft_transfers_block_height ON nft_transfers (block_height);
+
+-- One row per traded token of a transfer
+CREATE TABLE nft_trades (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    price TEXT NOT NULL,
+    -- 'near', or the account ID of the fungible token contract
+    currency TEXT NOT NULL,
+    marketplace_id TEXT NOT NULL,
+    buyer_id TEXT NOT NULL,
+    approval_id INTEGER,
+    declared_balance TEXT NOT NULL,
+    max_len_payout INTEGER,
+    -- NULL if the payout is unknown
+    seller_proceeds TEXT,
+    marketplace_fee TEXT,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+
+CREATE TABLE nft_trade_royalties (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id, account_id)
+);
+
+CREATE TABLE nft_burns (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    authorized_id TEXT,
+    memo TEXT,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    spam_score REAL NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_burns_contract_token ON nft_burns (contract_id, token_id);
+
+CREATE TABLE nft_metadata_updates (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    memo TEXT,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    spam_score REAL NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id)
+);
+CREATE INDEX nft_metadata_updates_contract_token ON nft_metadata_updates (contract_id, token_id);
+
+CREATE TABLE nft_contract_metadata_updates (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    contract_id TEXT NOT NULL,
+    memo TEXT,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    spam_score REAL NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index)
+);
+
+-- Share (receipt_id, log_index, event_index) with the transfer they were found in
+CREATE TABLE nft_payout_anomalies (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    event_index INTEGER NOT NULL,
+    token_id TEXT NOT NULL,
+    -- 'sum_mismatch' or 'too_many_recipients'
+    kind TEXT NOT NULL,
+    contract_id TEXT NOT NULL,
+    marketplace_id TEXT NOT NULL,
+    declared_balance TEXT NOT NULL,
+    payout_sum TEXT NOT NULL,
+    max_len_payout INTEGER,
+    payout_len INTEGER NOT NULL,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    PRIMARY KEY (receipt_id, log_index, event_index, token_id, kind)
+);
+
+CREATE TABLE nft_invalid_logs (
+    receipt_id TEXT NOT NULL,
+    log_index INTEGER NOT NULL,
+    contract_id TEXT NOT NULL,
+    log TEXT NOT NULL,
+    -- 'bad_json', 'unknown_event', 'wrong_version' or 'validation_failure'
+    reason TEXT NOT NULL,
+    transaction_id TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    block_timestamp_nanosec INTEGER NOT NULL,
+    PRIMARY KEY (receipt_id, log_index)
+);
+
+-- Derived from mints, transfers and burns: burned tokens have no row
+CREATE TABLE nft_owners (
+    contract_id TEXT NOT NULL,
+    token_id TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    -- Block of the mint or transfer that gave the token to owner_id
+    block_height INTEGER NOT NULL,
+    PRIMARY KEY (contract_id, token_id)
+);
+CREATE INDEX nft_owners_owner ON nft_owners (owner_id);
+
+-- Last block written by each indexer, committed together with its events
+CREATE TABLE nft_checkpoints (
+    name TEXT PRIMARY KEY,
+    block_height INTEGER NOT NULL
+);
@@ -0,0 +1,423 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, NftInvalidLog, NftPayoutAnomaly,
+};
+
+/// Forwards every call to several handlers, in the order they were added.
+///
+/// Tuples of up to 4 handlers also implement [`NftEventHandler`], with every sink
+/// being [`FailureMode::FailFast`].
+#[derive(Default)]
+pub struct FanOutHandler {
+    sinks: Vec<Sink>,
+}
+
+/// What happens when a sink returns an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureMode {
+    /// The error is returned right away, sinks after this one don't get the call.
+    ///
+    /// The indexer then restarts from the block that failed, so sinks before this
+    /// one get that block again even if they have already flushed it. They should
+    /// skip or overwrite blocks they already have, like all sinks in this crate do.
+    FailFast,
+    /// The error is logged and the call continues to the next sink.
+    ///
+    /// If a flush fails, the sink keeps the events of that block, and the flush is
+    /// retried with the same height before the sink gets any event of a later block.
+    /// Events are dropped for this sink until the retry succeeds.
+    ///
+    /// If any other call fails, the sink may have only part of the block, so it's
+    /// dropped, discarding or rolling back what it hasn't flushed, and gets no more
+    /// calls until the indexer restarts.
+    BestEffort,
+}
+
+struct Sink {
+    failure_mode: FailureMode,
+    /// `None` once the sink has been disabled after an error
+    handler: Option<Box<dyn NftEventHandler<Error = FanOutError>>>,
+    /// Block whose flush failed, only for [`FailureMode::BestEffort`] sinks
+    unflushed_block_height: Option<BlockHeight>,
+}
+
+impl Sink {
+    /// Retries the failed flush, so that the events of that block aren't flushed
+    /// with the height of the next block. False if it failed again.
+    async fn retry_flush(&mut self) -> bool {
+        let (Some(block_height), Some(handler)) = (self.unflushed_block_height, &mut self.handler)
+        else {
+            return true;
+        };
+        match handler.flush_events(block_height).await {
+            Ok(()) => {
+                self.unflushed_block_height = None;
+                true
+            }
+            Err(err) => {
+                log::warn!("{err}, dropping events until block {block_height} is flushed");
+                false
+            }
+        }
+    }
+
+    /// Drops the handler, so that the events it got of the current block are never
+    /// flushed without the ones it failed to handle
+    fn disable(&mut self, err: FanOutError) {
+        log::error!("{err}, disabling the sink until the indexer restarts");
+        self.handler = None;
+    }
+}
+
+impl FanOutHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `name` is used in logs and errors to tell sinks apart
+    pub fn with_sink(
+        mut self,
+        name: impl Into<String>,
+        handler: impl NftEventHandler + 'static,
+        failure_mode: FailureMode,
+    ) -> Self {
+        self.sinks.push(Sink {
+            failure_mode,
+            handler: Some(Box::new(NamedSink {
+                name: name.into(),
+                handler,
+            })),
+            unflushed_block_height: None,
+        });
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct FanOutError {
+    pub sink: String,
+    pub source: Box<dyn Error + Send + Sync>,
+}
+
+impl Display for FanOutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sink {} failed: {}", self.sink, self.source)
+    }
+}
+
+impl Error for FanOutError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// Attaches the name of the sink to its errors
+struct NamedSink<H> {
+    name: String,
+    handler: H,
+}
+
+impl<H: NftEventHandler> NamedSink<H> {
+    fn error(&self, err: H::Error) -> FanOutError {
+        FanOutError {
+            sink: self.name.clone(),
+            source: Box::new(err),
+        }
+    }
+}
+
+#[async_trait]
+impl<H: NftEventHandler> NftEventHandler for NamedSink<H> {
+    type Error = FanOutError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self.handler.handle_mint(mint, context).await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self.handler.handle_transfer(transfer, context).await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self.handler.handle_burn(burn, context).await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self
+            .handler
+            .handle_metadata_update(metadata_update, context)
+            .await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self
+            .handler
+            .handle_contract_metadata_update(contract_metadata_update, context)
+            .await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self.handler.handle_payout_anomaly(anomaly, context).await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let result = self.handler.handle_invalid_log(invalid_log, context).await;
+        result.map_err(|err| self.error(err))
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        let result = self.handler.flush_events(block_height).await;
+        result.map_err(|err| self.error(err))
+    }
+}
+
+/// Calls `$method` on every sink of a [`FanOutHandler`]
+macro_rules! fan_out {
+    ($self:ident, $method:ident ( $($arg:expr),* )) => {{
+        for sink in &mut $self.sinks {
+            if !sink.retry_flush().await {
+                continue;
+            }
+            let Some(handler) = &mut sink.handler else {
+                continue;
+            };
+            let result = handler.$method($($arg),*).await;
+            if let Err(err) = result {
+                match sink.failure_mode {
+                    FailureMode::FailFast => return Err(err),
+                    FailureMode::BestEffort => sink.disable(err),
+                }
+            }
+        }
+        Ok(())
+    }};
+}
+
+#[async_trait]
+impl NftEventHandler for FanOutHandler {
+    type Error = FanOutError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(self, handle_mint(mint.clone(), context.clone()))
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(self, handle_transfer(transfer.clone(), context.clone()))
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(self, handle_burn(burn.clone(), context.clone()))
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(
+            self,
+            handle_metadata_update(metadata_update.clone(), context.clone())
+        )
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(
+            self,
+            handle_contract_metadata_update(contract_metadata_update.clone(), context.clone())
+        )
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(
+            self,
+            handle_payout_anomaly(anomaly.clone(), context.clone())
+        )
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        fan_out!(
+            self,
+            handle_invalid_log(invalid_log.clone(), context.clone())
+        )
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        for sink in &mut self.sinks {
+            if !sink.retry_flush().await {
+                continue;
+            }
+            let Some(handler) = &mut sink.handler else {
+                continue;
+            };
+            if let Err(err) = handler.flush_events(block_height).await {
+                match sink.failure_mode {
+                    FailureMode::FailFast => return Err(err),
+                    FailureMode::BestEffort => {
+                        log::warn!("{err}");
+                        sink.unflushed_block_height = Some(block_height);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls `$method` on every element of a tuple, stopping at the first error
+macro_rules! fan_out_tuple {
+    ($self:ident, $method:ident ( $($arg:expr),* ), $($index:tt)+) => {{
+        $(
+            $self
+                .$index
+                .$method($($arg),*)
+                .await
+                .map_err(|err| FanOutError {
+                    sink: stringify!($index).to_owned(),
+                    source: Box::new(err),
+                })?;
+        )+
+        Ok(())
+    }};
+}
+
+macro_rules! impl_tuple_handler {
+    ($($name:ident $index:tt),+) => {
+        #[async_trait]
+        impl<$($name: NftEventHandler),+> NftEventHandler for ($($name,)+) {
+            type Error = FanOutError;
+
+            async fn handle_mint(
+                &mut self,
+                mint: ExtendedNftMintEvent,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, handle_mint(mint.clone(), context.clone()), $($index)+)
+            }
+
+            async fn handle_transfer(
+                &mut self,
+                transfer: ExtendedNftTransferEvent,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, handle_transfer(transfer.clone(), context.clone()), $($index)+)
+            }
+
+            async fn handle_burn(
+                &mut self,
+                burn: ExtendedNftBurnEvent,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, handle_burn(burn.clone(), context.clone()), $($index)+)
+            }
+
+            async fn handle_metadata_update(
+                &mut self,
+                metadata_update: ExtendedNftMetadataUpdateEvent,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, handle_metadata_update(metadata_update.clone(), context.clone()), $($index)+)
+            }
+
+            async fn handle_contract_metadata_update(
+                &mut self,
+                contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(
+                    self,
+                    handle_contract_metadata_update(contract_metadata_update.clone(), context.clone()),
+                    $($index)+
+                )
+            }
+
+            async fn handle_payout_anomaly(
+                &mut self,
+                anomaly: NftPayoutAnomaly,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, handle_payout_anomaly(anomaly.clone(), context.clone()), $($index)+)
+            }
+
+            async fn handle_invalid_log(
+                &mut self,
+                invalid_log: NftInvalidLog,
+                context: EventContext,
+            ) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, handle_invalid_log(invalid_log.clone(), context.clone()), $($index)+)
+            }
+
+            async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+                fan_out_tuple!(self, flush_events(block_height), $($index)+)
+            }
+        }
+    };
+}
+
+impl_tuple_handler!(A 0, B 1);
+impl_tuple_handler!(A 0, B 1, C 2);
+impl_tuple_handler!(A 0, B 1, C 2, D 3);
@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use serde::Deserialize;
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, NftInvalidLog, NftPayoutAnomaly,
+};
+
+/// Only forwards events that pass `filter` to `handler`. Flushes are always forwarded.
+pub struct FilteredHandler<H: NftEventHandler> {
+    pub handler: H,
+    pub filter: EventFilter,
+}
+
+impl<H: NftEventHandler> FilteredHandler<H> {
+    pub fn new(handler: H, filter: EventFilter) -> Self {
+        Self { handler, filter }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EventFilter {
+    /// NFT contracts that emitted the event
+    pub contracts: AccountFilter,
+    /// `EventContext::tx_sender_id`
+    pub tx_senders: AccountFilter,
+    /// Owners of the tokens, both old and new for transfers. Events that don't have
+    /// an owner, like metadata updates, are not filtered by owner.
+    pub owners: AccountFilter,
+}
+
+impl EventFilter {
+    /// Reads a JSON file like
+    /// `{"contracts": {"allow": ["*.mintbase1.near"], "deny": ["spam.mintbase1.near"]}}`
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let contents = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&contents)?)
+    }
+
+    /// Reads `$NFT_FILTER_FILE` if set, then adds comma-separated patterns from
+    /// `$NFT_{CONTRACTS,TX_SENDERS,OWNERS}_{ALLOW,DENY}`
+    pub fn from_env() -> Result<Self, std::io::Error> {
+        let mut filter = match std::env::var("NFT_FILTER_FILE") {
+            Ok(path) => Self::from_file(path)?,
+            Err(_) => Self::default(),
+        };
+        for (prefix, account_filter) in [
+            ("NFT_CONTRACTS", &mut filter.contracts),
+            ("NFT_TX_SENDERS", &mut filter.tx_senders),
+            ("NFT_OWNERS", &mut filter.owners),
+        ] {
+            if let Ok(patterns) = std::env::var(format!("{prefix}_ALLOW")) {
+                account_filter.allow.extend(patterns.split(','));
+            }
+            if let Ok(patterns) = std::env::var(format!("{prefix}_DENY")) {
+                account_filter.deny.extend(patterns.split(','));
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches<'a>(
+        &self,
+        context: &EventContext,
+        owners: impl IntoIterator<Item = &'a AccountId>,
+    ) -> bool {
+        self.contracts.matches(&context.contract_id)
+            && self.tx_senders.matches(&context.tx_sender_id)
+            && self.owners.matches_any(owners)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccountFilter {
+    /// If not empty, only these accounts pass
+    pub allow: AccountList,
+    /// These accounts never pass, even if they're in `allow`
+    pub deny: AccountList,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, account_id: &AccountId) -> bool {
+        (self.allow.is_empty() || self.allow.contains(account_id))
+            && !self.deny.contains(account_id)
+    }
+
+    /// Passes if any of the accounts is allowed and none of them is denied. Always
+    /// passes if there are no accounts.
+    pub fn matches_any<'a>(&self, account_ids: impl IntoIterator<Item = &'a AccountId>) -> bool {
+        let mut allowed = self.allow.is_empty();
+        let mut empty = true;
+        for account_id in account_ids {
+            empty = false;
+            if self.deny.contains(account_id) {
+                return false;
+            }
+            allowed |= self.allow.contains(account_id);
+        }
+        empty || allowed
+    }
+}
+
+/// Account IDs, or patterns like `*.mintbase1.near` that match all subaccounts
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(from = "Vec<String>")]
+pub struct AccountList {
+    exact: HashSet<String>,
+    /// Including the leading `.`
+    suffixes: Vec<String>,
+}
+
+impl AccountList {
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty()
+    }
+
+    pub fn contains(&self, account_id: &AccountId) -> bool {
+        self.exact.contains(account_id.as_str())
+            || self
+                .suffixes
+                .iter()
+                .any(|suffix| account_id.as_str().ends_with(suffix.as_str()))
+    }
+}
+
+impl<'a> Extend<&'a str> for AccountList {
+    fn extend<T: IntoIterator<Item = &'a str>>(&mut self, patterns: T) {
+        for pattern in patterns {
+            let pattern = pattern.trim();
+            if pattern.is_empty() {
+                continue;
+            }
+            if let Some(suffix) = pattern.strip_prefix('*') {
+                self.suffixes.push(suffix.to_owned());
+            } else {
+                self.exact.insert(pattern.to_owned());
+            }
+        }
+    }
+}
+
+impl From<Vec<String>> for AccountList {
+    fn from(patterns: Vec<String>) -> Self {
+        let mut list = Self::default();
+        list.extend(patterns.iter().map(String::as_str));
+        list
+    }
+}
+
+#[async_trait]
+impl<H: NftEventHandler> NftEventHandler for FilteredHandler<H> {
+    type Error = H::Error;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(&context, [&mint.event.owner_id]) {
+            return Ok(());
+        }
+        self.handler.handle_mint(mint, context).await
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(
+            &context,
+            [&transfer.event.old_owner_id, &transfer.event.new_owner_id],
+        ) {
+            return Ok(());
+        }
+        self.handler.handle_transfer(transfer, context).await
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(&context, [&burn.event.owner_id]) {
+            return Ok(());
+        }
+        self.handler.handle_burn(burn, context).await
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(&context, []) {
+            return Ok(());
+        }
+        self.handler
+            .handle_metadata_update(metadata_update, context)
+            .await
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(&context, []) {
+            return Ok(());
+        }
+        self.handler
+            .handle_contract_metadata_update(contract_metadata_update, context)
+            .await
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(&context, []) {
+            return Ok(());
+        }
+        self.handler.handle_payout_anomaly(anomaly, context).await
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        if !self.filter.matches(&context, []) {
+            return Ok(());
+        }
+        self.handler.handle_invalid_log(invalid_log, context).await
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        self.handler.flush_events(block_height).await
+    }
+}
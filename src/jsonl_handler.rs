@@ -0,0 +1,384 @@
+//! Appends events to newline-delimited JSON files, for archiving to object storage
+//! or grepping without running any service. Files are rotated by block range or
+//! size, and `manifest.json` lists the finished ones with the blocks they cover.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, NftInvalidLog, NftPayoutAnomaly,
+};
+
+const MANIFEST_FILE: &str = "manifest.json";
+/// Extension of the file that is being written to
+const PARTIAL_EXTENSION: &str = "part";
+
+/// Each line is `{"event": "nft_mint", "context": {...}, "data": {...}}`, where
+/// `event` is the name of the Redis stream the event would go to.
+///
+/// The file that is being written to has a `.part` extension. If the indexer
+/// stops without [`PushToJsonlFiles::close`], [`PushToJsonlFiles::open`] finishes
+/// it on the next start without its last block, and blocks that are already in
+/// finished files are skipped.
+pub struct PushToJsonlFiles {
+    directory: PathBuf,
+    rotation: RotationPolicy,
+    /// Lines of the current block, written by `flush_events`
+    buffer: Vec<u8>,
+    buffered_events: u64,
+    current: Option<OpenFile>,
+    manifest: Vec<ManifestEntry>,
+}
+
+/// When to start a new file. Blocks are never split between files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationPolicy {
+    /// Files cover aligned ranges of this many blocks, for example with 100_000
+    /// blocks 117_000_000..117_100_000 go to one file
+    pub blocks_per_file: Option<BlockHeight>,
+    /// A file is finished after the block that makes it at least this many bytes
+    pub max_file_size: Option<u64>,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            blocks_per_file: Some(100_000),
+            max_file_size: Some(256 * 1024 * 1024),
+        }
+    }
+}
+
+/// A finished file. Blocks between `first_block_height` and `last_block_height`
+/// that have no events in the file had no events at all.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// Name of the file, relative to the directory of the manifest
+    pub file: String,
+    pub first_block_height: BlockHeight,
+    pub last_block_height: BlockHeight,
+    pub events: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    files: Vec<ManifestEntry>,
+}
+
+struct OpenFile {
+    file: File,
+    path: PathBuf,
+    first_block_height: BlockHeight,
+    last_block_height: BlockHeight,
+    events: u64,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct Line<'a, T> {
+    event: &'static str,
+    context: &'a EventContext,
+    data: &'a T,
+}
+
+/// The part of a line that is needed to recover an unfinished file
+#[derive(Deserialize)]
+struct LineHeader {
+    context: LineContext,
+}
+
+#[derive(Deserialize)]
+struct LineContext {
+    block_height: BlockHeight,
+}
+
+impl PushToJsonlFiles {
+    /// Creates `directory` if it doesn't exist and finishes the files that were
+    /// left unfinished by a previous run
+    pub fn open(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        let manifest = match fs::read(directory.join(MANIFEST_FILE)) {
+            Ok(manifest) => serde_json::from_slice::<Manifest>(&manifest)?.files,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        let mut handler = Self {
+            directory,
+            rotation: RotationPolicy::default(),
+            buffer: Vec::new(),
+            buffered_events: 0,
+            current: None,
+            manifest,
+        };
+        handler.recover_partial_files()?;
+        Ok(handler)
+    }
+
+    pub fn with_rotation(mut self, rotation: RotationPolicy) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Files finished so far, in block order
+    pub fn manifest(&self) -> &[ManifestEntry] {
+        &self.manifest
+    }
+
+    /// Finishes the current file, so that it's included in the manifest
+    pub fn close(mut self) -> io::Result<()> {
+        self.finish_file()
+    }
+
+    fn last_block_height(&self) -> Option<BlockHeight> {
+        match &self.current {
+            Some(current) => Some(current.last_block_height),
+            None => self.manifest.last().map(|entry| entry.last_block_height),
+        }
+    }
+
+    fn add_line<T: Serialize>(&mut self, event: &'static str, context: &EventContext, data: &T) {
+        serde_json::to_writer(
+            &mut self.buffer,
+            &Line {
+                event,
+                context,
+                data,
+            },
+        )
+        .expect("Failed to serialize event");
+        self.buffer.push(b'\n');
+        self.buffered_events += 1;
+    }
+
+    /// Renames the current file to its final name and adds it to the manifest
+    fn finish_file(&mut self) -> io::Result<()> {
+        let Some(current) = self.current.take() else {
+            return Ok(());
+        };
+        current.file.sync_all()?;
+        let name = format!(
+            "nft-events-{}-{}.jsonl",
+            current.first_block_height, current.last_block_height
+        );
+        fs::rename(&current.path, self.directory.join(&name))?;
+        self.manifest.push(ManifestEntry {
+            file: name,
+            first_block_height: current.first_block_height,
+            last_block_height: current.last_block_height,
+            events: current.events,
+            size: current.size,
+        });
+        // Files recovered on startup can be older than the ones in the manifest
+        self.manifest.sort_by_key(|entry| entry.first_block_height);
+        self.write_manifest()
+    }
+
+    /// Replaces the manifest atomically, so readers never see a partial one
+    fn write_manifest(&self) -> io::Result<()> {
+        let path = self.directory.join(MANIFEST_FILE);
+        let temporary_path = path.with_extension("json.tmp");
+        let manifest = serde_json::to_vec_pretty(&Manifest {
+            files: self.manifest.clone(),
+        })?;
+        fs::write(&temporary_path, manifest)?;
+        fs::rename(temporary_path, path)
+    }
+
+    fn recover_partial_files(&mut self) -> io::Result<()> {
+        let mut partial_files = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == PARTIAL_EXTENSION)
+            {
+                partial_files.push(path);
+            }
+        }
+        partial_files.sort();
+        for path in partial_files {
+            match recover_partial_file(&path)? {
+                Some(current) => {
+                    log::warn!("Finishing {} left by a previous run", path.display());
+                    self.current = Some(current);
+                    self.finish_file()?;
+                }
+                None => fs::remove_file(&path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cuts off the last block of the file, since the previous run may have stopped
+/// in the middle of writing it, and it will be written again. None if no other
+/// blocks are in the file.
+fn recover_partial_file(path: &Path) -> io::Result<Option<OpenFile>> {
+    let contents = fs::read(path)?;
+    // (block height, end of the line including the newline) of complete lines
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while let Some(len) = contents[start..].iter().position(|byte| *byte == b'\n') {
+        let end = start + len + 1;
+        let line = &contents[start..end - 1];
+        if !line.is_empty() {
+            let block_height = serde_json::from_slice::<LineHeader>(line)?
+                .context
+                .block_height;
+            lines.push((block_height, end));
+        }
+        start = end;
+    }
+    let Some(&(last_block_height, _)) = lines.last() else {
+        return Ok(None);
+    };
+    lines.retain(|(block_height, _)| *block_height != last_block_height);
+    let (Some(&(first_block_height, _)), Some(&(last_block_height, end))) =
+        (lines.first(), lines.last())
+    else {
+        return Ok(None);
+    };
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(end as u64)?;
+    Ok(Some(OpenFile {
+        file,
+        path: path.to_owned(),
+        first_block_height,
+        last_block_height,
+        events: lines.len() as u64,
+        size: end as u64,
+    }))
+}
+
+#[async_trait]
+impl NftEventHandler for PushToJsonlFiles {
+    type Error = io::Error;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line("nft_mint", &context, &mint);
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line("nft_transfer", &context, &transfer);
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line("nft_burn", &context, &burn);
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line("nft_metadata_update", &context, &metadata_update);
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line(
+            "nft_contract_metadata_update",
+            &context,
+            &contract_metadata_update,
+        );
+        Ok(())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line("nft_payout_anomaly", &context, &anomaly);
+        Ok(())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.add_line("nft_invalid_log", &context, &invalid_log);
+        Ok(())
+    }
+
+    /// Appends the block to the current file, so a file never ends in the middle
+    /// of a block unless the indexer is killed while writing it
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        if self
+            .last_block_height()
+            .is_some_and(|last_block_height| last_block_height >= block_height)
+        {
+            log::warn!("Block {block_height} is already in the JSONL files, skipping");
+            self.buffer.clear();
+            self.buffered_events = 0;
+            return Ok(());
+        }
+        if let (Some(current), Some(blocks_per_file)) =
+            (&self.current, self.rotation.blocks_per_file)
+        {
+            if current.first_block_height / blocks_per_file != block_height / blocks_per_file {
+                self.finish_file()?;
+            }
+        }
+
+        if self.current.is_none() {
+            let path = self.directory.join(format!(
+                "nft-events-{block_height}.jsonl.{PARTIAL_EXTENSION}"
+            ));
+            self.current = Some(OpenFile {
+                file: File::create(&path)?,
+                path,
+                first_block_height: block_height,
+                last_block_height: block_height,
+                events: 0,
+                size: 0,
+            });
+        }
+        let current = self.current.as_mut().expect("File was just opened");
+        current.file.write_all(&self.buffer)?;
+        current.last_block_height = block_height;
+        current.events += self.buffered_events;
+        current.size += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.buffered_events = 0;
+
+        if self
+            .rotation
+            .max_file_size
+            .is_some_and(|max_file_size| current.size >= max_file_size)
+        {
+            self.finish_file()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! Publishes the same payloads as [`PushToRedisStream`](crate::redis_handler::PushToRedisStream)
+//! to Kafka (or Redpanda) topics. Messages are keyed by `contract_id`, so all
+//! events of a contract land in one partition and keep their order.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use serde::Serialize;
+
+use crate::stream_events::{
+    NftBurnEvent, NftContractMetadataUpdateEvent, NftInvalidLogEvent, NftMetadataUpdateEvent,
+    NftMintEvent, NftPayoutAnomalyEvent, NftTransferEvent,
+};
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, NftInvalidLog, NftPayoutAnomaly,
+};
+
+/// Messages are buffered until [`NftEventHandler::flush_events`], which returns
+/// once the broker has acknowledged all of them. Delivery is at least once: a
+/// block that is re-processed after a failed flush can be published again, so
+/// consumers should deduplicate by `(receipt_id, log_index, event_index)`.
+pub struct PushToKafka {
+    producer: FutureProducer,
+    topics: KafkaTopics,
+    messages: Vec<KafkaMessage>,
+}
+
+/// Topic for each event type
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaTopics {
+    pub mint: String,
+    pub transfer: String,
+    pub burn: String,
+    pub metadata_update: String,
+    pub contract_metadata_update: String,
+    pub payout_anomaly: String,
+    pub invalid_log: String,
+}
+
+impl Default for KafkaTopics {
+    /// Same names as the Redis streams
+    fn default() -> Self {
+        Self::prefixed("")
+    }
+}
+
+impl KafkaTopics {
+    /// Names of the Redis streams, prefixed with `prefix`
+    pub fn prefixed(prefix: &str) -> Self {
+        Self {
+            mint: format!("{prefix}nft_mint"),
+            transfer: format!("{prefix}nft_transfer"),
+            burn: format!("{prefix}nft_burn"),
+            metadata_update: format!("{prefix}nft_metadata_update"),
+            contract_metadata_update: format!("{prefix}nft_contract_metadata_update"),
+            payout_anomaly: format!("{prefix}nft_payout_anomaly"),
+            invalid_log: format!("{prefix}nft_invalid_log"),
+        }
+    }
+}
+
+/// A message waiting for [`NftEventHandler::flush_events`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaMessage {
+    topic: String,
+    key: String,
+    payload: String,
+    timestamp_millis: i64,
+}
+
+impl KafkaMessage {
+    /// Keyed by `contract_id`, with the block timestamp as the message timestamp
+    pub fn new(
+        topic: &str,
+        contract_id: &AccountId,
+        block_timestamp_nanosec: u128,
+        payload: &impl Serialize,
+    ) -> Self {
+        Self {
+            topic: topic.to_owned(),
+            key: contract_id.to_string(),
+            payload: serde_json::to_string(payload).expect("Failed to serialize event"),
+            timestamp_millis: (block_timestamp_nanosec / 1_000_000) as i64,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// JSON of the event, same as the Redis stream entry
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
+
+    pub fn timestamp_millis(&self) -> i64 {
+        self.timestamp_millis
+    }
+}
+
+impl PushToKafka {
+    /// Enables idempotence and `acks=all` on top of `config`, so that retries
+    /// inside the producer don't duplicate or reorder messages
+    pub fn new(mut config: ClientConfig) -> Result<Self, KafkaError> {
+        let producer = config
+            .set("enable.idempotence", "true")
+            .set("acks", "all")
+            .create()?;
+        Ok(Self {
+            producer,
+            topics: KafkaTopics::default(),
+            messages: Vec::new(),
+        })
+    }
+
+    /// `brokers` is a comma-separated list of `host:port`
+    pub fn connect(brokers: &str) -> Result<Self, KafkaError> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        Self::new(config)
+    }
+
+    pub fn with_topics(mut self, topics: KafkaTopics) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Messages of the current block that haven't been flushed yet
+    pub fn messages(&self) -> &[KafkaMessage] {
+        &self.messages
+    }
+}
+
+async fn delivered(delivery: DeliveryFuture) -> Result<(), KafkaError> {
+    match delivery.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((err, _message))) => Err(err),
+        Err(_canceled) => Err(KafkaError::Canceled),
+    }
+}
+
+#[async_trait]
+impl NftEventHandler for PushToKafka {
+    type Error = KafkaError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftMintEvent::new(mint, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.mint,
+            &event.event.contract_id,
+            event.event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftTransferEvent::new(transfer, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.transfer,
+            &event.contract_id,
+            event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftBurnEvent::new(burn, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.burn,
+            &event.event.contract_id,
+            event.event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftMetadataUpdateEvent::new(metadata_update, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.metadata_update,
+            &event.contract_id,
+            event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftContractMetadataUpdateEvent::new(contract_metadata_update, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.contract_metadata_update,
+            &event.contract_id,
+            event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftPayoutAnomalyEvent::new(anomaly, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.payout_anomaly,
+            &event.contract_id,
+            event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let event = NftInvalidLogEvent::new(invalid_log, context);
+        self.messages.push(KafkaMessage::new(
+            &self.topics.invalid_log,
+            &event.contract_id,
+            event.block_timestamp_nanosec,
+            &event,
+        ));
+        Ok(())
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), Self::Error> {
+        let mut deliveries = VecDeque::new();
+        for message in &self.messages {
+            let mut record = FutureRecord::to(&message.topic)
+                .key(&message.key)
+                .payload(&message.payload)
+                .timestamp(message.timestamp_millis);
+            loop {
+                match self.producer.send_result(record) {
+                    Ok(delivery) => {
+                        deliveries.push_back(delivery);
+                        break;
+                    }
+                    // Wait until the oldest message is delivered to make room in
+                    // the producer queue
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                        record = returned;
+                        match deliveries.pop_front() {
+                            Some(delivery) => delivered(delivery).await?,
+                            None => tokio::time::sleep(Duration::from_millis(100)).await,
+                        }
+                    }
+                    Err((err, _record)) => return Err(err),
+                }
+            }
+        }
+        for delivery in deliveries {
+            delivered(delivery).await?;
+        }
+        self.messages.clear();
+        Ok(())
+    }
+}
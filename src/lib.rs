@@ -1,4 +1,22 @@
+pub mod fan_out_handler;
+pub mod filter_handler;
+pub mod jsonl_handler;
+#[cfg(feature = "kafka")]
+pub mod kafka_handler;
+pub mod metrics;
+#[cfg(feature = "parquet")]
+pub mod parquet_handler;
+#[cfg(feature = "postgres")]
+pub mod postgres_handler;
+pub mod recorded_blocks;
 pub mod redis_handler;
+pub mod spam;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_handler;
+pub mod stream_events;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod wash_trade;
 
 use std::collections::HashMap;
 
@@ -12,19 +30,66 @@ use inindexer::near_utils::{
     NftTransferEvent, NftTransferLog,
 };
 use inindexer::{IncompleteTransaction, Indexer, TransactionReceipt};
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use spam::{SpamClassifier, SpamConfig};
+use wash_trade::{WashTradeAnalyzer, WashTradeConfig};
 
 #[async_trait]
 pub trait NftEventHandler: Send + Sync {
-    async fn handle_mint(&mut self, mint: ExtendedNftMintEvent, context: EventContext);
-    async fn handle_transfer(&mut self, transfer: ExtendedNftTransferEvent, context: EventContext);
-    async fn handle_burn(&mut self, burn: ExtendedNftBurnEvent, context: EventContext);
+    /// Returned from handler methods and propagated to the runner through [`NftIndexer`]
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error>;
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error>;
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error>;
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error>;
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error>;
+    /// Only called if [`NftIndexerConfig::validate_payouts`] is enabled. Ignores
+    /// anomalies by default.
+    async fn handle_payout_anomaly(
+        &mut self,
+        _anomaly: NftPayoutAnomaly,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Called for NEP-171 event logs that couldn't be handled as any of the events
+    /// above. Ignores them by default, they are still counted in [`metrics`].
+    async fn handle_invalid_log(
+        &mut self,
+        _invalid_log: NftInvalidLog,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
 
     /// Called after each block
-    async fn flush_events(&mut self, block_height: BlockHeight);
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error>;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct ExtendedNftMintEvent {
     pub event: NftMintEvent,
 }
@@ -35,62 +100,423 @@ impl ExtendedNftMintEvent {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct ExtendedNftTransferEvent {
     pub event: NftTransferEvent,
     pub trade: NftTradeDetails,
+    /// Some if this transfer was emitted by `nft_resolve_transfer` and moves the token
+    /// back because the receiver of `nft_transfer_call` returned `true`. Contains the
+    /// ID of the `nft_transfer_call` receipt that emitted the transfer being reverted.
+    pub reverted_transfer_receipt_id: Option<CryptoHash>,
+    /// Set by [`NftIndexer`] if [`WashTradeAnalyzer`] thinks any of the trades
+    /// is between related accounts. Always false if wash trade detection is off.
+    pub suspected_wash_trade: bool,
 }
 
 impl ExtendedNftTransferEvent {
-    pub fn from_event(event: NftTransferEvent, receipt: &TransactionReceipt) -> Self {
-        let mut prices = vec![None; event.token_ids.len()];
-        if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
-            for action in actions {
-                if let ActionView::FunctionCall {
+    /// `receipt` is the one that emitted the transfer log. The `nft_transfer_payout`
+    /// call that caused the transfer is looked up in all receipts of `transaction`.
+    pub fn from_event(
+        event: NftTransferEvent,
+        receipt: &TransactionReceipt,
+        transaction: &IncompleteTransaction,
+        config: &NftIndexerConfig,
+    ) -> Self {
+        let payout_calls = PayoutCall::find_all(transaction);
+        let contract_id = &receipt.receipt.receipt.receiver_id;
+        let mut trades = vec![None; event.token_ids.len()];
+        let mut payout_anomalies = Vec::new();
+        for (index, token_id) in event.token_ids.iter().enumerate() {
+            let Some(call) = payout_calls
+                .iter()
+                .filter(|call| {
+                    call.receipt.receipt.receipt.receiver_id == *contract_id
+                        && call.args.token_id == *token_id
+                        && call.args.receiver_id == event.new_owner_id
+                })
+                // Prefer the call from the same receipt, it's the case for most marketplaces
+                .min_by_key(|call| {
+                    (
+                        call.receipt.receipt.receipt.receipt_id
+                            != receipt.receipt.receipt.receipt_id,
+                        call.receipt.block_height,
+                        call.receipt.receipt.receipt.receipt_id,
+                        call.action_index,
+                    )
+                })
+            else {
+                continue;
+            };
+            let args = &call.args;
+            let marketplace_id = &call.receipt.receipt.receipt.predecessor_id;
+            let price = match (&call.payout, config.price_source) {
+                (Some(payout), PriceSource::PayoutSum) => payout.payout.values().sum::<Balance>(),
+                (None, PriceSource::PayoutSum) | (_, PriceSource::DeclaredBalance) => args.balance,
+            };
+            if config.validate_payouts {
+                if let Some(payout) = &call.payout {
+                    payout_anomalies.extend(NftPayoutAnomaly::detect(args, payout, marketplace_id));
+                }
+            }
+            let currency =
+                detect_price_currency(marketplace_id, contract_id, token_id, transaction);
+            let payout = call.payout.as_ref().map(|payout| {
+                PayoutBreakdown::new(&payout.payout, &event.old_owner_id, marketplace_id)
+            });
+            trades[index] = Some(TokenTrade {
+                price,
+                currency,
+                payout,
+                marketplace_id: marketplace_id.clone(),
+                buyer_id: transaction.transaction.transaction.signer_id.clone(),
+                approval_id: args.approval_id,
+                declared_balance: args.balance,
+                max_len_payout: args.max_len_payout,
+            });
+        }
+        let reverted_transfer_receipt_id = find_reverted_transfer(&event, receipt, transaction);
+        ExtendedNftTransferEvent {
+            event,
+            reverted_transfer_receipt_id,
+            suspected_wash_trade: false,
+            trade: NftTradeDetails {
+                token_prices_near: trades
+                    .iter()
+                    .map(|trade| {
+                        trade
+                            .as_ref()
+                            .filter(|trade| trade.currency == PriceCurrency::Near)
+                            .map(|trade| trade.price)
+                    })
+                    .collect(),
+                token_trades: trades,
+                payout_anomalies,
+            },
+        }
+    }
+}
+
+/// If `receipt` is `nft_resolve_transfer` that moves a token back to its previous
+/// owner, finds the `nft_transfer_call` receipt that sent it to the receiver
+fn find_reverted_transfer(
+    event: &NftTransferEvent,
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+) -> Option<CryptoHash> {
+    if !has_function_call(receipt, "nft_resolve_transfer") {
+        return None;
+    }
+    let contract_id = &receipt.receipt.receipt.receiver_id;
+    transaction
+        .receipts
+        .values()
+        .flatten()
+        .filter(|transfer_call_receipt| {
+            transfer_call_receipt.receipt.receipt.receiver_id == *contract_id
+        })
+        .filter(|transfer_call_receipt| {
+            let ReceiptEnumView::Action { actions, .. } =
+                &transfer_call_receipt.receipt.receipt.receipt
+            else {
+                return false;
+            };
+            actions.iter().any(|action| {
+                let ActionView::FunctionCall {
                     method_name, args, ..
                 } = action
-                {
-                    if method_name == "nft_transfer_payout" {
-                        if let ExecutionStatusView::SuccessValue(value) =
-                            &receipt.receipt.execution_outcome.outcome.status
-                        {
+                else {
+                    return false;
+                };
+                method_name == "nft_transfer_call"
+                    && serde_json::from_slice::<NftTransferCallArgs>(args).is_ok_and(|args| {
+                        args.receiver_id == event.old_owner_id
+                            && event.token_ids.contains(&args.token_id)
+                    })
+            })
+        })
+        .map(|transfer_call_receipt| {
+            (
+                transfer_call_receipt.block_height,
+                transfer_call_receipt.receipt.receipt.receipt_id,
+            )
+        })
+        .min()
+        .map(|(_, receipt_id)| receipt_id)
+}
+
+fn has_function_call(receipt: &TransactionReceipt, method: &str) -> bool {
+    if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
+        actions.iter().any(|action| {
+            matches!(action, ActionView::FunctionCall { method_name, .. } if method_name == method)
+        })
+    } else {
+        false
+    }
+}
+
+/// A successful `nft_transfer_payout` function call action
+struct PayoutCall<'a> {
+    receipt: &'a TransactionReceipt,
+    action_index: usize,
+    args: NftTransferPayoutArgs,
+    /// None if the return value of the call can't be known. A receipt only has the
+    /// return value of its last action, so this is always None for all but the last
+    /// `nft_transfer_payout` in a batch.
+    payout: Option<PayoutResponse>,
+}
+
+impl<'a> PayoutCall<'a> {
+    fn find_all(transaction: &'a IncompleteTransaction) -> Vec<Self> {
+        let mut calls = Vec::new();
+        for receipt in transaction.receipts.values().flatten() {
+            if !receipt.is_successful(false) {
+                continue;
+            }
+            if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
+                for (action_index, action) in actions.iter().enumerate() {
+                    if let ActionView::FunctionCall {
+                        method_name, args, ..
+                    } = action
+                    {
+                        if method_name == "nft_transfer_payout" {
                             if let Ok(args) = serde_json::from_slice::<NftTransferPayoutArgs>(args)
                             {
-                                if let Some(index) = event
-                                    .token_ids
-                                    .iter()
-                                    .position(|token_id| **token_id == args.token_id)
-                                {
-                                    if let Ok(payout) =
-                                        serde_json::from_slice::<PayoutResponse>(value)
-                                    {
-                                        // Is this always the same as args.balance?
-                                        let price = payout.payout.values().sum::<Balance>();
-                                        prices[index] = Some(price);
-                                    }
-                                }
+                                let payout = if action_index == actions.len() - 1 {
+                                    return_value(receipt, transaction).and_then(|value| {
+                                        serde_json::from_slice::<PayoutResponse>(value).ok()
+                                    })
+                                } else {
+                                    None
+                                };
+                                calls.push(PayoutCall {
+                                    receipt,
+                                    action_index,
+                                    args,
+                                    payout,
+                                });
                             }
                         }
                     }
                 }
             }
         }
-        ExtendedNftTransferEvent {
-            event,
-            trade: NftTradeDetails {
-                token_prices_near: prices,
-            },
+        calls
+    }
+}
+
+/// Follows `SuccessReceiptId` to the receipt that actually returned the value
+fn return_value<'a>(
+    receipt: &'a TransactionReceipt,
+    transaction: &'a IncompleteTransaction,
+) -> Option<&'a [u8]> {
+    const MAX_DEPTH: usize = 16;
+    let mut receipt = receipt;
+    for _ in 0..MAX_DEPTH {
+        match &receipt.receipt.execution_outcome.outcome.status {
+            ExecutionStatusView::SuccessValue(value) => return Some(value.as_slice()),
+            ExecutionStatusView::SuccessReceiptId(receipt_id) => {
+                receipt = transaction.receipts.get(receipt_id)?.as_ref()?;
+            }
+            _ => return None,
         }
     }
+    None
+}
+
+/// Marketplaces that accept fungible tokens receive them through `ft_transfer_call`,
+/// so the sale is settled in a fungible token if the marketplace that called
+/// `nft_transfer_payout` successfully handled an `ft_on_transfer` call in the same
+/// transaction whose `msg` refers to the token being sold. The amount isn't
+/// compared with the price, since it's in units of the fungible token.
+fn detect_price_currency(
+    marketplace_id: &AccountId,
+    contract_id: &AccountId,
+    token_id: &str,
+    transaction: &IncompleteTransaction,
+) -> PriceCurrency {
+    transaction
+        .receipts
+        .values()
+        .flatten()
+        .filter(|receipt| {
+            receipt.receipt.receipt.receiver_id == *marketplace_id && receipt.is_successful(false)
+        })
+        .filter(|receipt| {
+            let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+                return false;
+            };
+            actions.iter().any(|action| {
+                let ActionView::FunctionCall {
+                    method_name, args, ..
+                } = action
+                else {
+                    return false;
+                };
+                method_name == "ft_on_transfer"
+                    && serde_json::from_slice::<FtOnTransferArgs>(args)
+                        .is_ok_and(|args| args.refers_to(contract_id, token_id))
+            })
+        })
+        .map(|receipt| {
+            (
+                receipt.block_height,
+                receipt.receipt.receipt.receipt_id,
+                &receipt.receipt.receipt.predecessor_id,
+            )
+        })
+        .min()
+        .map_or(PriceCurrency::Near, |(_, _, ft_contract_id)| {
+            PriceCurrency::Ft(ft_contract_id.clone())
+        })
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct NftTradeDetails {
-    /// None if it's a simple transfer, Some if it's a trade. Guaranteed to have the same length as NftTransferEvent::token_ids
+    /// None if it's a simple transfer or a trade settled in a fungible token, Some if it's a trade for NEAR. Guaranteed to have the same length as NftTransferEvent::token_ids
+    #[serde(with = "dec_format_vec")]
     pub token_prices_near: Vec<Option<Balance>>,
+    /// None if it's a simple transfer, Some if it's a trade. Guaranteed to have the same length as NftTransferEvent::token_ids
+    pub token_trades: Vec<Option<TokenTrade>>,
+    /// Always empty unless [`NftIndexerConfig::validate_payouts`] is enabled
+    pub payout_anomalies: Vec<NftPayoutAnomaly>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenTrade {
+    /// Amount of `currency` paid for the token, in its smallest units. Either the
+    /// sum of the payout or `declared_balance`, see [`NftIndexerConfig::price_source`]
+    #[serde(with = "dec_format")]
+    pub price: Balance,
+    pub currency: PriceCurrency,
+    /// None if `nft_transfer_payout` was batched with other actions and its return
+    /// value is unknown, in which case `price` is always `declared_balance`
+    pub payout: Option<PayoutBreakdown>,
+    /// Account that called `nft_transfer_payout`, usually a marketplace contract
+    pub marketplace_id: AccountId,
+    /// Signer of the transaction that resulted in this trade
+    pub buyer_id: AccountId,
+    /// `approval_id` passed to `nft_transfer_payout`
+    #[serde(with = "dec_format")]
+    pub approval_id: Option<u64>,
+    /// `balance` passed to `nft_transfer_payout`
+    #[serde(with = "dec_format")]
+    pub declared_balance: Balance,
+    /// `max_len_payout` passed to `nft_transfer_payout`
+    pub max_len_payout: Option<u32>,
+}
+
+/// A payout that doesn't match what the marketplace asked for in `nft_transfer_payout`,
+/// usually caused by buggy royalty math in the NFT contract
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftPayoutAnomaly {
+    pub token_id: String,
+    pub marketplace_id: AccountId,
+    pub kind: PayoutAnomalyKind,
+    #[serde(with = "dec_format")]
+    pub declared_balance: Balance,
+    #[serde(with = "dec_format")]
+    pub payout_sum: Balance,
+    pub max_len_payout: Option<u32>,
+    pub payout_len: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutAnomalyKind {
+    /// Payout doesn't add up to `balance`
+    SumMismatch,
+    /// Payout has more recipients than `max_len_payout`
+    TooManyRecipients,
+}
+
+impl NftPayoutAnomaly {
+    fn detect(
+        args: &NftTransferPayoutArgs,
+        payout: &PayoutResponse,
+        marketplace_id: &AccountId,
+    ) -> Vec<Self> {
+        let payout_sum = payout.payout.values().sum::<Balance>();
+        let payout_len = payout.payout.len();
+        let mut kinds = Vec::new();
+        if payout_sum != args.balance {
+            kinds.push(PayoutAnomalyKind::SumMismatch);
+        }
+        if let Some(max_len_payout) = args.max_len_payout {
+            if payout_len > max_len_payout as usize {
+                kinds.push(PayoutAnomalyKind::TooManyRecipients);
+            }
+        }
+        kinds
+            .into_iter()
+            .map(|kind| NftPayoutAnomaly {
+                token_id: args.token_id.clone(),
+                marketplace_id: marketplace_id.clone(),
+                kind,
+                declared_balance: args.balance,
+                payout_sum,
+                max_len_payout: args.max_len_payout,
+                payout_len,
+            })
+            .collect()
+    }
+}
+
+/// Who received what from the `nft_transfer_payout` response. All amounts are
+/// in the currency of the trade.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PayoutBreakdown {
+    /// Amount received by the previous owner of the token
+    #[serde(with = "dec_format")]
+    pub seller_proceeds: Balance,
+    /// Amounts received by everyone else, usually creators of the collection
+    #[serde(with = "dec_format_map")]
+    pub royalties: HashMap<AccountId, Balance>,
+    /// Amount received by the marketplace, if it included itself in the payout
+    #[serde(with = "dec_format")]
+    pub marketplace_fee: Option<Balance>,
+}
+
+impl PayoutBreakdown {
+    fn new(
+        payout: &HashMap<AccountId, Balance>,
+        seller_id: &AccountId,
+        marketplace_id: &AccountId,
+    ) -> Self {
+        let mut seller_proceeds = 0;
+        let mut royalties = HashMap::new();
+        let mut marketplace_fee = None;
+        for (account_id, amount) in payout {
+            if account_id == seller_id {
+                seller_proceeds += amount;
+            } else if account_id == marketplace_id {
+                marketplace_fee = Some(*amount);
+            } else {
+                royalties.insert(account_id.clone(), *amount);
+            }
+        }
+        PayoutBreakdown {
+            seller_proceeds,
+            royalties,
+            marketplace_fee,
+        }
+    }
+
+    pub fn total(&self) -> Balance {
+        self.seller_proceeds
+            + self.royalties.values().sum::<Balance>()
+            + self.marketplace_fee.unwrap_or_default()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceCurrency {
+    Near,
+    /// NEP-141 fungible token contract
+    Ft(AccountId),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct ExtendedNftBurnEvent {
     pub event: NftBurnEvent,
 }
@@ -101,39 +527,355 @@ impl ExtendedNftBurnEvent {
     }
 }
 
-#[allow(dead_code)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExtendedNftMetadataUpdateEvent {
+    pub event: NftMetadataUpdateEvent,
+}
+
+impl ExtendedNftMetadataUpdateEvent {
+    pub fn from_event(event: NftMetadataUpdateEvent) -> Self {
+        ExtendedNftMetadataUpdateEvent { event }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExtendedNftContractMetadataUpdateEvent {
+    pub event: NftContractMetadataUpdateEvent,
+}
+
+impl ExtendedNftContractMetadataUpdateEvent {
+    pub fn from_event(event: NftContractMetadataUpdateEvent) -> Self {
+        ExtendedNftContractMetadataUpdateEvent { event }
+    }
+}
+
+/// `nft_metadata_update` event, added in NEP-171 v1.1.0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftMetadataUpdateEvent {
+    pub token_ids: Vec<String>,
+    pub memo: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NftMetadataUpdateLog(pub Vec<NftMetadataUpdateEvent>);
+
+/// `contract_metadata_update` event, added in NEP-171 v1.2.0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftContractMetadataUpdateEvent {
+    pub memo: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NftContractMetadataUpdateLog(pub Vec<NftContractMetadataUpdateEvent>);
+
+/// A log that claims to be a NEP-171 event but isn't one this indexer can handle
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftInvalidLog {
+    /// The log as emitted by the contract, including the `EVENT_JSON:` prefix
+    pub log: String,
+    pub reason: InvalidLogReason,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidLogReason {
+    /// Not valid JSON after the `EVENT_JSON:` prefix, or missing `standard`,
+    /// `version`, `event` or `data`
+    BadJson,
+    /// `event` is not one of the events defined by NEP-171
+    UnknownEvent,
+    /// Known event, but with a `standard` or `version` that isn't supported for it
+    WrongVersion,
+    /// Known event and version, but `data` doesn't match the standard
+    ValidationFailure,
+}
+
+/// A valid NEP-171 event log
+#[derive(Debug)]
+pub enum NftEventLog {
+    Mint(EventLogData<NftMintLog>),
+    Transfer(EventLogData<NftTransferLog>),
+    Burn(EventLogData<NftBurnLog>),
+    MetadataUpdate(EventLogData<NftMetadataUpdateLog>),
+    ContractMetadataUpdate(EventLogData<NftContractMetadataUpdateLog>),
+}
+
+/// Parses the `EVENT_JSON:` envelope once and `data` only as the type that `event`
+/// says it is. Returns `None` if the log is not a NEP-171 event log at all.
+pub fn parse_event_log(log: &str) -> Option<Result<NftEventLog, InvalidLogReason>> {
+    let json = log.strip_prefix("EVENT_JSON:")?;
+    let envelope = match serde_json::from_str::<EventLogEnvelope>(json) {
+        Ok(envelope) => envelope,
+        Err(_) if json.contains("\"nep171\"") => return Some(Err(InvalidLogReason::BadJson)),
+        Err(_) => return None,
+    };
+    if envelope.standard != "nep171" {
+        return None;
+    }
+    Some(match envelope.event.as_str() {
+        "nft_mint" => envelope
+            .into_valid_log(|log: &EventLogData<NftMintLog>| log.validate())
+            .map(NftEventLog::Mint),
+        "nft_transfer" => envelope
+            .into_valid_log(|log: &EventLogData<NftTransferLog>| log.validate())
+            .map(NftEventLog::Transfer),
+        "nft_burn" => envelope
+            .into_valid_log(|log: &EventLogData<NftBurnLog>| log.validate())
+            .map(NftEventLog::Burn),
+        "nft_metadata_update" => envelope
+            .into_valid_log(is_valid_metadata_update_log)
+            .map(NftEventLog::MetadataUpdate),
+        "contract_metadata_update" => envelope
+            .into_valid_log(is_valid_contract_metadata_update_log)
+            .map(NftEventLog::ContractMetadataUpdate),
+        _ => Err(InvalidLogReason::UnknownEvent),
+    })
+}
+
+/// `EVENT_JSON:` log with `data` left unparsed until `event` tells its type
+#[derive(Deserialize)]
+struct EventLogEnvelope<'a> {
+    standard: String,
+    version: String,
+    event: String,
+    #[serde(borrow)]
+    data: &'a RawValue,
+}
+
+impl EventLogEnvelope<'_> {
+    fn into_valid_log<T: DeserializeOwned>(
+        self,
+        is_valid: fn(&EventLogData<T>) -> bool,
+    ) -> Result<EventLogData<T>, InvalidLogReason> {
+        let data = serde_json::from_str(self.data.get())
+            .map_err(|_| InvalidLogReason::ValidationFailure)?;
+        let log = EventLogData {
+            standard: self.standard,
+            version: self.version,
+            event: self.event,
+            data,
+        };
+        if is_valid(&log) {
+            Ok(log)
+        } else {
+            Err(InvalidLogReason::WrongVersion)
+        }
+    }
+}
+
+impl NftInvalidLog {
+    /// Returns `None` if the log is a valid event, or not a NEP-171 event log at all
+    pub fn from_log(log: &str) -> Option<Self> {
+        match parse_event_log(log)? {
+            Ok(_) => None,
+            Err(reason) => Some(Self {
+                log: log.to_owned(),
+                reason,
+            }),
+        }
+    }
+}
+
+/// Events defined by NEP-171, the only values of `event` that reach validation
+const NEP171_EVENTS: [&str; 5] = [
+    "nft_mint",
+    "nft_transfer",
+    "nft_burn",
+    "nft_metadata_update",
+    "contract_metadata_update",
+];
+
+/// `event` of a NEP-171 log, if it's one of [`NEP171_EVENTS`]. Only used for logs
+/// that were already rejected, so parsing the envelope again doesn't matter.
+pub(crate) fn nep171_event_name(log: &str) -> Option<&'static str> {
+    let json = log.strip_prefix("EVENT_JSON:")?;
+    let envelope = serde_json::from_str::<EventLogEnvelope>(json).ok()?;
+    NEP171_EVENTS
+        .into_iter()
+        .find(|event| *event == envelope.event)
+}
+
+fn is_valid_metadata_update_log(log: &EventLogData<NftMetadataUpdateLog>) -> bool {
+    log.standard == "nep171"
+        && log.event == "nft_metadata_update"
+        && (log.version == "1.1.0" || log.version == "1.2.0")
+}
+
+fn is_valid_contract_metadata_update_log(log: &EventLogData<NftContractMetadataUpdateLog>) -> bool {
+    log.standard == "nep171" && log.event == "contract_metadata_update" && log.version == "1.2.0"
+}
+
 #[derive(Deserialize, Debug)]
 struct NftTransferPayoutArgs {
     receiver_id: AccountId,
     token_id: String,
     #[serde(with = "dec_format")]
     approval_id: Option<u64>,
-    memo: Option<String>,
     #[serde(with = "dec_format")]
     balance: Balance,
     max_len_payout: Option<u32>,
 }
 
-#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct NftTransferCallArgs {
+    receiver_id: AccountId,
+    token_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct FtOnTransferArgs {
+    msg: String,
+}
+
+impl FtOnTransferArgs {
+    /// Whether `msg` is JSON that mentions the NFT, either as separate token and
+    /// contract IDs or as a `<token_id>:<contract_id>` key like Mintbase uses
+    fn refers_to(&self, contract_id: &AccountId, token_id: &str) -> bool {
+        let Ok(msg) = serde_json::from_str::<serde_json::Value>(&self.msg) else {
+            return false;
+        };
+        contains_string(&msg, &format!("{token_id}:{contract_id}"))
+            || (contains_string(&msg, token_id) && contains_string(&msg, contract_id.as_str()))
+    }
+}
+
+fn contains_string(value: &serde_json::Value, string: &str) -> bool {
+    match value {
+        serde_json::Value::String(value) => value == string,
+        serde_json::Value::Array(values) => {
+            values.iter().any(|value| contains_string(value, string))
+        }
+        serde_json::Value::Object(fields) => {
+            fields.values().any(|value| contains_string(value, string))
+        }
+        _ => false,
+    }
+}
+
+/// `dec_format` for each element of a `Vec<Option<Balance>>`, so amounts above
+/// 2^53 don't lose precision in JSON consumers
+pub(crate) mod dec_format_vec {
+    use inindexer::near_indexer_primitives::types::Balance;
+    use inindexer::near_utils::dec_format;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Amount(#[serde(with = "dec_format")] Option<Balance>);
+
+    pub fn serialize<S: Serializer>(
+        amounts: &[Option<Balance>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(amounts.iter().map(|amount| Amount(*amount)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Option<Balance>>, D::Error> {
+        Ok(Vec::<Amount>::deserialize(deserializer)?
+            .into_iter()
+            .map(|Amount(amount)| amount)
+            .collect())
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct PayoutResponse {
     #[serde(with = "dec_format_map")]
     payout: HashMap<AccountId, Balance>,
 }
 
-pub struct NftIndexer<T: NftEventHandler + Send + Sync + 'static>(pub T);
+pub struct NftIndexer<T: NftEventHandler + Send + Sync + 'static> {
+    pub handler: T,
+    pub config: NftIndexerConfig,
+    spam_classifier: SpamClassifier,
+    wash_trade_analyzer: WashTradeAnalyzer,
+    /// Events of the current block that haven't been passed to the handler yet
+    queued_events: Vec<(QueuedEvent, EventContext)>,
+    /// Position of every receipt of the block `receipt_indexes_block_height`
+    receipt_indexes: HashMap<CryptoHash, usize>,
+    receipt_indexes_block_height: Option<BlockHeight>,
+}
+
+/// Event waiting in [`NftIndexer`] to be passed to the handler
+enum QueuedEvent {
+    Mint(ExtendedNftMintEvent),
+    Transfer(ExtendedNftTransferEvent),
+    Burn(ExtendedNftBurnEvent),
+    MetadataUpdate(ExtendedNftMetadataUpdateEvent),
+    ContractMetadataUpdate(ExtendedNftContractMetadataUpdateEvent),
+    PayoutAnomaly(NftPayoutAnomaly),
+    InvalidLog(NftInvalidLog),
+}
 
-#[async_trait]
-impl<T: NftEventHandler + Send + Sync + 'static> Indexer for NftIndexer<T> {
-    type Error = String;
+impl<T: NftEventHandler + Send + Sync + 'static> NftIndexer<T> {
+    pub fn new(handler: T) -> Self {
+        Self {
+            handler,
+            config: NftIndexerConfig::default(),
+            spam_classifier: SpamClassifier::new(),
+            wash_trade_analyzer: WashTradeAnalyzer::new(),
+            queued_events: Vec::new(),
+            receipt_indexes: HashMap::new(),
+            receipt_indexes_block_height: None,
+        }
+    }
 
-    async fn on_receipt(
+    pub fn with_config(mut self, config: NftIndexerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn spam_classifier(&self) -> &SpamClassifier {
+        &self.spam_classifier
+    }
+
+    pub fn wash_trade_analyzer(&self) -> &WashTradeAnalyzer {
+        &self.wash_trade_analyzer
+    }
+
+    /// Fills in the spam score, or returns `None` if the event should be dropped
+    fn classify(&self, mut context: EventContext) -> Option<EventContext> {
+        let Some(spam_config) = &self.config.spam else {
+            return Some(context);
+        };
+        context.spam_score =
+            self.spam_classifier
+                .score(spam_config, context.transaction_id, &context.contract_id);
+        if spam_config.should_drop(context.spam_score) {
+            log::debug!(
+                "Dropping event in {} with spam score {}",
+                context.receipt_id,
+                context.spam_score
+            );
+            None
+        } else {
+            Some(context)
+        }
+    }
+
+    /// For receipts that aren't part of a block stream. Calls the handler for every
+    /// NEP-171 event the receipt emitted, with `receipt_index` 0.
+    pub async fn process_receipt(
         &mut self,
         receipt: &TransactionReceipt,
         transaction: &IncompleteTransaction,
-        _block: &StreamerMessage,
-    ) -> Result<(), Self::Error> {
-        let get_context_lazy = || {
+    ) -> Result<(), T::Error> {
+        self.queue_receipt(0, receipt, transaction);
+        self.process_queued_events().await
+    }
+
+    /// What [`Indexer::on_receipt`] does. Queues every NEP-171 event the receipt
+    /// emitted, to be passed to the handler by [`NftIndexer::process_queued_events`].
+    /// `receipt_index` is the position of the receipt in its block, see
+    /// [`EventContext::receipt_index`].
+    pub fn queue_receipt(
+        &mut self,
+        receipt_index: usize,
+        receipt: &TransactionReceipt,
+        transaction: &IncompleteTransaction,
+    ) {
+        let get_context_lazy = |log_index: usize, event_index: usize| {
             let tx_sender_id = receipt.receipt.receipt.predecessor_id.clone();
             let contract_id = receipt.receipt.receipt.receiver_id.clone();
             let transaction_id = transaction.transaction.transaction.hash;
@@ -143,74 +885,336 @@ impl<T: NftEventHandler + Send + Sync + 'static> Indexer for NftIndexer<T> {
             EventContext {
                 transaction_id,
                 receipt_id,
+                receipt_index,
+                log_index,
+                event_index,
                 block_height,
                 block_timestamp_nanosec,
                 tx_sender_id,
                 contract_id,
+                spam_score: 0.0,
             }
         };
+        if let Some(wash_trade_config) = &self.config.wash_trades {
+            if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
+                if receipt.is_successful(false)
+                    && actions
+                        .iter()
+                        .any(|action| matches!(action, ActionView::CreateAccount))
+                {
+                    self.wash_trade_analyzer.record_funding(
+                        wash_trade_config,
+                        &transaction.transaction.transaction.signer_id,
+                        &receipt.receipt.receipt.receiver_id,
+                    );
+                }
+            }
+        }
         if receipt.is_successful(false) {
-            for log in &receipt.receipt.execution_outcome.outcome.logs {
+            for (log_index, log) in receipt
+                .receipt
+                .execution_outcome
+                .outcome
+                .logs
+                .iter()
+                .enumerate()
+            {
                 if !log.contains("nep171") {
                     // Don't even start parsing logs if they don't even contain the NEP-171 standard
                     continue;
                 }
-                if let Ok(mint_log) = EventLogData::<NftMintLog>::deserialize(log) {
-                    if mint_log.validate() {
+                let event_log = match parse_event_log(log) {
+                    None => continue,
+                    Some(Ok(event_log)) => event_log,
+                    Some(Err(reason)) => {
+                        let invalid_log = NftInvalidLog {
+                            log: log.clone(),
+                            reason,
+                        };
+                        log::debug!("Invalid log: {invalid_log:?}");
+                        metrics::record_invalid_log(&invalid_log);
+                        let Some(context) = self.classify(get_context_lazy(log_index, 0)) else {
+                            continue;
+                        };
+                        self.queued_events
+                            .push((QueuedEvent::InvalidLog(invalid_log), context));
+                        continue;
+                    }
+                };
+                match event_log {
+                    NftEventLog::Mint(mint_log) => {
                         log::debug!("Mint log: {mint_log:?}");
-                        for mint in mint_log.data.0 {
-                            self.0
-                                .handle_mint(
-                                    ExtendedNftMintEvent::from_event(mint),
-                                    get_context_lazy(),
-                                )
-                                .await;
+                        for mint in &mint_log.data.0 {
+                            self.spam_classifier.record_mint(
+                                transaction.transaction.transaction.hash,
+                                &receipt.receipt.receipt.receiver_id,
+                                &transaction.transaction.transaction.signer_id,
+                                &mint.owner_id,
+                            );
+                        }
+                        for (event_index, mint) in mint_log.data.0.into_iter().enumerate() {
+                            let Some(context) =
+                                self.classify(get_context_lazy(log_index, event_index))
+                            else {
+                                continue;
+                            };
+                            metrics::record_events("nft_mint", &context.contract_id, 1);
+                            self.queued_events.push((
+                                QueuedEvent::Mint(ExtendedNftMintEvent::from_event(mint)),
+                                context,
+                            ));
                         }
                     }
-                }
-                if let Ok(transfer_log) = EventLogData::<NftTransferLog>::deserialize(log) {
-                    if transfer_log.validate() {
+                    NftEventLog::Transfer(transfer_log) => {
                         log::debug!("Transfer log: {transfer_log:?}");
-                        for transfer in transfer_log.data.0 {
-                            self.0
-                                .handle_transfer(
-                                    ExtendedNftTransferEvent::from_event(transfer, receipt),
-                                    get_context_lazy(),
-                                )
-                                .await;
+                        for (event_index, transfer) in transfer_log.data.0.into_iter().enumerate() {
+                            let mut transfer = ExtendedNftTransferEvent::from_event(
+                                transfer,
+                                receipt,
+                                transaction,
+                                &self.config,
+                            );
+                            if let Some(wash_trade_config) = &self.config.wash_trades {
+                                transfer.suspected_wash_trade = self.wash_trade_analyzer.analyze(
+                                    wash_trade_config,
+                                    &receipt.receipt.receipt.receiver_id,
+                                    &transfer,
+                                    receipt.block_timestamp_nanosec,
+                                );
+                            }
+                            if transfer.trade.token_trades.iter().any(Option::is_some) {
+                                self.spam_classifier
+                                    .record_trade(&receipt.receipt.receipt.receiver_id);
+                            }
+                            let Some(context) =
+                                self.classify(get_context_lazy(log_index, event_index))
+                            else {
+                                continue;
+                            };
+                            metrics::record_events("nft_transfer", &context.contract_id, 1);
+                            metrics::record_events(
+                                "nft_trade",
+                                &context.contract_id,
+                                transfer.trade.token_trades.iter().flatten().count() as u64,
+                            );
+                            for anomaly in &transfer.trade.payout_anomalies {
+                                log::debug!("Payout anomaly: {anomaly:?}");
+                                self.queued_events.push((
+                                    QueuedEvent::PayoutAnomaly(anomaly.clone()),
+                                    context.clone(),
+                                ));
+                            }
+                            self.queued_events
+                                .push((QueuedEvent::Transfer(transfer), context));
                         }
                     }
-                }
-                if let Ok(burn_log) = EventLogData::<NftBurnLog>::deserialize(log) {
-                    if burn_log.validate() {
+                    NftEventLog::Burn(burn_log) => {
                         log::debug!("Burn log: {burn_log:?}");
-                        for burn in burn_log.data.0 {
-                            self.0
-                                .handle_burn(
-                                    ExtendedNftBurnEvent::from_event(burn),
-                                    get_context_lazy(),
-                                )
-                                .await;
+                        for (event_index, burn) in burn_log.data.0.into_iter().enumerate() {
+                            let Some(context) =
+                                self.classify(get_context_lazy(log_index, event_index))
+                            else {
+                                continue;
+                            };
+                            metrics::record_events("nft_burn", &context.contract_id, 1);
+                            self.queued_events.push((
+                                QueuedEvent::Burn(ExtendedNftBurnEvent::from_event(burn)),
+                                context,
+                            ));
+                        }
+                    }
+                    NftEventLog::MetadataUpdate(metadata_update_log) => {
+                        log::debug!("Metadata update log: {metadata_update_log:?}");
+                        for (event_index, metadata_update) in
+                            metadata_update_log.data.0.into_iter().enumerate()
+                        {
+                            let Some(context) =
+                                self.classify(get_context_lazy(log_index, event_index))
+                            else {
+                                continue;
+                            };
+                            metrics::record_events("nft_metadata_update", &context.contract_id, 1);
+                            self.queued_events.push((
+                                QueuedEvent::MetadataUpdate(
+                                    ExtendedNftMetadataUpdateEvent::from_event(metadata_update),
+                                ),
+                                context,
+                            ));
                         }
                     }
+                    NftEventLog::ContractMetadataUpdate(contract_metadata_update_log) => {
+                        log::debug!(
+                            "Contract metadata update log: {contract_metadata_update_log:?}"
+                        );
+                        for (event_index, contract_metadata_update) in
+                            contract_metadata_update_log.data.0.into_iter().enumerate()
+                        {
+                            let Some(context) =
+                                self.classify(get_context_lazy(log_index, event_index))
+                            else {
+                                continue;
+                            };
+                            metrics::record_events(
+                                "contract_metadata_update",
+                                &context.contract_id,
+                                1,
+                            );
+                            self.queued_events.push((
+                                QueuedEvent::ContractMetadataUpdate(
+                                    ExtendedNftContractMetadataUpdateEvent::from_event(
+                                        contract_metadata_update,
+                                    ),
+                                ),
+                                context,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes the queued events to the handler in the order of the block, see
+    /// [`EventContext`]. Called by [`Indexer::process_block_end`] before the flush.
+    pub async fn process_queued_events(&mut self) -> Result<(), T::Error> {
+        let mut queued_events = std::mem::take(&mut self.queued_events);
+        // Stable, so payout anomalies stay before the transfer they share a position with
+        queued_events.sort_by_key(|(_, context)| {
+            (
+                context.receipt_index,
+                context.log_index,
+                context.event_index,
+            )
+        });
+        for (event, context) in queued_events {
+            match event {
+                QueuedEvent::Mint(mint) => self.handler.handle_mint(mint, context).await?,
+                QueuedEvent::Transfer(transfer) => {
+                    self.handler.handle_transfer(transfer, context).await?
+                }
+                QueuedEvent::Burn(burn) => self.handler.handle_burn(burn, context).await?,
+                QueuedEvent::MetadataUpdate(metadata_update) => {
+                    self.handler
+                        .handle_metadata_update(metadata_update, context)
+                        .await?
+                }
+                QueuedEvent::ContractMetadataUpdate(contract_metadata_update) => {
+                    self.handler
+                        .handle_contract_metadata_update(contract_metadata_update, context)
+                        .await?
+                }
+                QueuedEvent::PayoutAnomaly(anomaly) => {
+                    self.handler.handle_payout_anomaly(anomaly, context).await?
+                }
+                QueuedEvent::InvalidLog(invalid_log) => {
+                    self.handler
+                        .handle_invalid_log(invalid_log, context)
+                        .await?
                 }
             }
         }
         Ok(())
     }
 
+    /// Position of the receipt in `block`, or after all receipts of the block if
+    /// it's not there
+    fn receipt_index(&mut self, receipt: &TransactionReceipt, block: &StreamerMessage) -> usize {
+        let block_height = block.block.header.height;
+        if self.receipt_indexes_block_height != Some(block_height) {
+            self.receipt_indexes = block
+                .shards
+                .iter()
+                .flat_map(|shard| &shard.receipt_execution_outcomes)
+                .enumerate()
+                .map(|(receipt_index, outcome)| (outcome.receipt.receipt_id, receipt_index))
+                .collect();
+            self.receipt_indexes_block_height = Some(block_height);
+        }
+        let receipt_id = receipt.receipt.receipt.receipt_id;
+        self.receipt_indexes
+            .get(&receipt_id)
+            .copied()
+            .unwrap_or_else(|| {
+                log::warn!("Receipt {receipt_id} is not in block {block_height}");
+                self.receipt_indexes.len()
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NftIndexerConfig {
+    pub price_source: PriceSource,
+    /// Cross-check every payout against the `balance` and `max_len_payout` passed
+    /// to `nft_transfer_payout` and report mismatches as [`NftPayoutAnomaly`]
+    pub validate_payouts: bool,
+    /// Score events with [`SpamClassifier`] heuristics. Scores are always 0.0 if
+    /// this is `None`.
+    pub spam: Option<SpamConfig>,
+    /// Flag trades with [`WashTradeAnalyzer`] heuristics
+    pub wash_trades: Option<WashTradeConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PriceSource {
+    /// Sum of the amounts returned by `nft_transfer_payout`
+    #[default]
+    PayoutSum,
+    /// `balance` passed to `nft_transfer_payout` by the marketplace
+    DeclaredBalance,
+}
+
+#[async_trait]
+impl<T: NftEventHandler + Send + Sync + 'static> Indexer for NftIndexer<T> {
+    type Error = T::Error;
+
+    async fn on_receipt(
+        &mut self,
+        receipt: &TransactionReceipt,
+        transaction: &IncompleteTransaction,
+        block: &StreamerMessage,
+    ) -> Result<(), Self::Error> {
+        let receipt_index = self.receipt_index(receipt, block);
+        self.queue_receipt(receipt_index, receipt, transaction);
+        Ok(())
+    }
+
     async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
-        self.0.flush_events(block.block.header.height).await;
+        self.process_queued_events().await?;
+        self.spam_classifier.end_block();
+        if let Some(wash_trade_config) = &self.config.wash_trades {
+            self.wash_trade_analyzer.end_block(
+                wash_trade_config,
+                block.block.header.timestamp_nanosec as u128,
+            );
+        }
+        self.handler.flush_events(block.block.header.height).await?;
+        metrics::record_block_height(block.block.header.height);
         Ok(())
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Events of a block are passed to the handler in the order of the chunks, then
+/// the receipts of each chunk, then the logs of each receipt and the events of
+/// each log, so sorting by `(block_height, receipt_index, log_index, event_index)`
+/// gives the same order. Payout anomalies share their position with their
+/// transfer and come right before it. `(receipt_id, log_index, event_index)`
+/// uniquely identifies an event, except for payout anomalies.
+#[derive(Serialize, Clone, Debug, PartialEq)]
 pub struct EventContext {
     pub transaction_id: CryptoHash,
     pub receipt_id: CryptoHash,
+    /// Position of the receipt in the block, counting the receipt execution
+    /// outcomes of all chunks in the order of the chunks
+    pub receipt_index: usize,
+    /// Position of the log in the logs of the receipt
+    pub log_index: usize,
+    /// Position of the event in the `data` array of the log
+    pub event_index: usize,
     pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
     pub block_timestamp_nanosec: u128,
     pub tx_sender_id: AccountId,
     pub contract_id: AccountId,
+    /// See [`SpamClassifier::score`]
+    pub spam_score: f64,
 }
@@ -1,13 +1,35 @@
 #[cfg(test)]
 mod tests;
 
+use std::time::Duration;
+
 use inindexer::neardata::NeardataProvider;
 use inindexer::{
     run_indexer, AutoContinue, BlockRange, IndexerOptions, PreprocessTransactionsSettings,
 };
+use nft_indexer::fan_out_handler::{FailureMode, FanOutHandler};
+use nft_indexer::filter_handler::{EventFilter, FilteredHandler};
+use nft_indexer::jsonl_handler::{PushToJsonlFiles, RotationPolicy};
+#[cfg(feature = "kafka")]
+use nft_indexer::kafka_handler::{KafkaTopics, PushToKafka};
+use nft_indexer::metrics;
+#[cfg(feature = "parquet")]
+use nft_indexer::parquet_handler::PushToParquet;
+#[cfg(feature = "postgres")]
+use nft_indexer::postgres_handler::PushToPostgres;
 use nft_indexer::redis_handler;
+use nft_indexer::spam::SpamConfig;
+#[cfg(feature = "sqlite")]
+use nft_indexer::sqlite_handler::PushToSqlite;
+use nft_indexer::wash_trade::WashTradeConfig;
+use nft_indexer::{NftIndexer, NftIndexerConfig, PriceSource};
 use redis::aio::ConnectionManager;
-use redis_handler::PushToRedisStream;
+use redis_handler::{FlushMode, PushToRedisStream, RetryPolicy};
+
+/// How long to wait before restarting the indexer after a handler error
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// How often the height of the chain head is checked for the block lag metric
+const CHAIN_HEAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() {
@@ -24,40 +46,265 @@ async fn main() {
     .unwrap();
     let connection = ConnectionManager::new(client).await.unwrap();
 
-    let mut indexer = nft_indexer::NftIndexer(PushToRedisStream::new(connection, 10_000).await);
+    let default_retry_policy = RetryPolicy::default();
+    let retry_policy = RetryPolicy {
+        max_attempts: std::env::var("REDIS_RETRY_MAX_ATTEMPTS")
+            .map(|attempts| attempts.parse().expect("Invalid $REDIS_RETRY_MAX_ATTEMPTS"))
+            .unwrap_or(default_retry_policy.max_attempts),
+        base_delay: std::env::var("REDIS_RETRY_BASE_DELAY_MS")
+            .map(|delay| {
+                Duration::from_millis(delay.parse().expect("Invalid $REDIS_RETRY_BASE_DELAY_MS"))
+            })
+            .unwrap_or(default_retry_policy.base_delay),
+        max_delay: std::env::var("REDIS_RETRY_MAX_DELAY_MS")
+            .map(|delay| {
+                Duration::from_millis(delay.parse().expect("Invalid $REDIS_RETRY_MAX_DELAY_MS"))
+            })
+            .unwrap_or(default_retry_policy.max_delay),
+        jitter: std::env::var("REDIS_RETRY_JITTER")
+            .map(|jitter| jitter.parse().expect("Invalid $REDIS_RETRY_JITTER"))
+            .unwrap_or(default_retry_policy.jitter),
+    };
+    retry_policy.validate().expect("Invalid Redis retry policy");
+
+    let flush_mode = match std::env::var("REDIS_LAST_BLOCK_KEY") {
+        Ok(last_block_key) => FlushMode::Atomic { last_block_key },
+        Err(_) => FlushMode::Sequential,
+    };
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: if cfg!(debug_assertions) { 0 } else { 100 },
-                postfetch_blocks: 0,
+    if let Ok(metrics_addr) = std::env::var("METRICS_ADDR") {
+        let metrics_addr = metrics_addr.parse().expect("Invalid $METRICS_ADDR");
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr).await {
+                log::error!("Metrics server stopped: {err}");
+            }
+        });
+        tokio::spawn(metrics::poll_chain_head(
+            std::env::var("NEAR_RPC_URL")
+                .unwrap_or_else(|_| "https://rpc.mainnet.near.org".to_owned()),
+            CHAIN_HEAD_POLL_INTERVAL,
+        ));
+    }
+
+    let filter = EventFilter::from_env().expect("Failed to load event filter");
+    let mut spam_config = SpamConfig {
+        drop_threshold: std::env::var("NFT_SPAM_DROP_THRESHOLD")
+            .ok()
+            .map(|threshold| threshold.parse().expect("Invalid $NFT_SPAM_DROP_THRESHOLD")),
+        traded_contracts: std::env::var("NFT_SPAM_TRADED_CONTRACTS_FILE")
+            .ok()
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .expect("Failed to read $NFT_SPAM_TRADED_CONTRACTS_FILE")
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(|contract_id| contract_id.parse().expect("Invalid account ID"))
+                    .collect()
             }),
-            ..IndexerOptions::default_with_range(if std::env::args().len() > 1 {
-                // For debugging
-                let msg = "Usage: `indexer` or `indexer [start-block] [end-block]`";
-                BlockRange::Range {
-                    start_inclusive: std::env::args()
-                        .nth(1)
-                        .expect(msg)
-                        .replace(['_', ',', ' ', '.'], "")
-                        .parse()
-                        .expect(msg),
-                    end_exclusive: Some(
-                        std::env::args()
-                            .nth(2)
-                            .expect(msg)
-                            .replace(['_', ',', ' ', '.'], "")
-                            .parse()
-                            .expect(msg),
-                    ),
-                }
-            } else {
-                BlockRange::AutoContinue(AutoContinue::default())
+        ..Default::default()
+    };
+    let mut wash_trade_config = WashTradeConfig {
+        ignored_funders: std::env::var("NFT_WASH_TRADE_IGNORED_FUNDERS")
+            .map(|funders| {
+                funders
+                    .split(',')
+                    .map(|funder| funder.trim().parse().expect("Invalid account ID"))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        funders: std::env::var("NFT_WASH_TRADE_FUNDERS_FILE")
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .expect("Failed to read $NFT_WASH_TRADE_FUNDERS_FILE")
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(|line| {
+                        let (account_id, funder_id) =
+                            line.split_once(',').expect("Expected account_id,funder_id");
+                        (
+                            account_id.trim().parse().expect("Invalid account ID"),
+                            funder_id.trim().parse().expect("Invalid account ID"),
+                        )
+                    })
+                    .collect()
             })
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let price_source = match std::env::var("NFT_PRICE_SOURCE").as_deref() {
+        Ok("payout_sum") | Err(_) => PriceSource::PayoutSum,
+        Ok("declared_balance") => PriceSource::DeclaredBalance,
+        Ok(price_source) => panic!(
+            "Invalid $NFT_PRICE_SOURCE {price_source}, expected payout_sum or declared_balance"
+        ),
+    };
+
+    let jsonl_directory = std::env::var("JSONL_DIR").ok();
+    // `0` or `none` turns a limit off
+    let jsonl_rotation = RotationPolicy {
+        blocks_per_file: match std::env::var("JSONL_BLOCKS_PER_FILE").as_deref() {
+            Err(_) => RotationPolicy::default().blocks_per_file,
+            Ok("0" | "none") => None,
+            Ok(blocks) => Some(blocks.parse().expect("Invalid $JSONL_BLOCKS_PER_FILE")),
         },
-    )
-    .await
-    .expect("Indexer run failed");
+        max_file_size: match std::env::var("JSONL_MAX_FILE_SIZE").as_deref() {
+            Err(_) => RotationPolicy::default().max_file_size,
+            Ok("0" | "none") => None,
+            Ok(size) => Some(size.parse().expect("Invalid $JSONL_MAX_FILE_SIZE")),
+        },
+    };
+
+    #[cfg(feature = "postgres")]
+    let database_url = std::env::var("DATABASE_URL").ok();
+    #[cfg(feature = "sqlite")]
+    let sqlite_path = std::env::var("SQLITE_PATH").ok();
+    #[cfg(feature = "parquet")]
+    let parquet_directory = std::env::var("PARQUET_DIR").ok();
+    #[cfg(feature = "kafka")]
+    let kafka_brokers = std::env::var("KAFKA_BROKERS").ok();
+    #[cfg(feature = "kafka")]
+    let kafka_topics =
+        KafkaTopics::prefixed(&std::env::var("KAFKA_TOPIC_PREFIX").unwrap_or_default());
+
+    let range = if std::env::args().len() > 1 {
+        // For debugging
+        let msg = "Usage: `indexer` or `indexer [start-block] [end-block]`";
+        Some((
+            std::env::args()
+                .nth(1)
+                .expect(msg)
+                .replace(['_', ',', ' ', '.'], "")
+                .parse()
+                .expect(msg),
+            std::env::args()
+                .nth(2)
+                .expect(msg)
+                .replace(['_', ',', ' ', '.'], "")
+                .parse()
+                .expect(msg),
+        ))
+    } else {
+        None
+    };
+
+    loop {
+        // A fresh handler on every run, so that events buffered for a block that
+        // failed to flush aren't pushed again when the block is re-processed
+        let sinks = FanOutHandler::new().with_sink(
+            "redis",
+            PushToRedisStream::new(connection.clone(), 10_000)
+                .await
+                .with_retry_policy(retry_policy.clone())
+                .with_flush_mode(flush_mode.clone()),
+            FailureMode::FailFast,
+        );
+        let sinks = match &jsonl_directory {
+            Some(jsonl_directory) => sinks.with_sink(
+                "jsonl",
+                PushToJsonlFiles::open(jsonl_directory)
+                    .expect("Failed to open JSONL directory")
+                    .with_rotation(jsonl_rotation.clone()),
+                FailureMode::FailFast,
+            ),
+            None => sinks,
+        };
+        #[cfg(feature = "postgres")]
+        let sinks = match &database_url {
+            Some(database_url) => match PushToPostgres::connect(database_url).await {
+                Ok(postgres) => sinks.with_sink("postgres", postgres, FailureMode::FailFast),
+                Err(err) => {
+                    log::error!(
+                        "Failed to connect to Postgres, retrying in {RESTART_DELAY:?}: {err:?}"
+                    );
+                    tokio::time::sleep(RESTART_DELAY).await;
+                    continue;
+                }
+            },
+            None => sinks,
+        };
+        #[cfg(feature = "sqlite")]
+        let sinks = match &sqlite_path {
+            Some(sqlite_path) => sinks.with_sink(
+                "sqlite",
+                PushToSqlite::open(sqlite_path).expect("Failed to open SQLite database"),
+                FailureMode::FailFast,
+            ),
+            None => sinks,
+        };
+        #[cfg(feature = "parquet")]
+        let sinks = match &parquet_directory {
+            Some(parquet_directory) => sinks.with_sink(
+                "parquet",
+                PushToParquet::new(parquet_directory),
+                FailureMode::FailFast,
+            ),
+            None => sinks,
+        };
+        #[cfg(feature = "kafka")]
+        let sinks = match &kafka_brokers {
+            Some(kafka_brokers) => sinks.with_sink(
+                "kafka",
+                PushToKafka::connect(kafka_brokers)
+                    .expect("Failed to create Kafka producer")
+                    .with_topics(kafka_topics.clone()),
+                FailureMode::FailFast,
+            ),
+            None => sinks,
+        };
+        let mut indexer = NftIndexer::new(FilteredHandler::new(sinks, filter.clone())).with_config(
+            NftIndexerConfig {
+                price_source,
+                validate_payouts: true,
+                spam: Some(spam_config.clone()),
+                wash_trades: Some(wash_trade_config.clone()),
+            },
+        );
+
+        let result = run_indexer(
+            &mut indexer,
+            NeardataProvider::mainnet(),
+            IndexerOptions {
+                preprocess_transactions: Some(PreprocessTransactionsSettings {
+                    prefetch_blocks: if cfg!(debug_assertions) { 0 } else { 100 },
+                    postfetch_blocks: 0,
+                }),
+                ..IndexerOptions::default_with_range(
+                    if let Some((start_inclusive, end_exclusive)) = range {
+                        BlockRange::Range {
+                            start_inclusive,
+                            end_exclusive: Some(end_exclusive),
+                        }
+                    } else {
+                        BlockRange::AutoContinue(AutoContinue::default())
+                    },
+                )
+            },
+        )
+        .await;
+
+        match result {
+            Ok(()) => break,
+            // AutoContinue resumes from the last processed block, so restarting
+            // retries the block that failed instead of skipping it
+            Err(err) if range.is_none() => {
+                if let Some(traded_contracts) = &mut spam_config.traded_contracts {
+                    traded_contracts
+                        .extend(indexer.spam_classifier().traded_contracts().iter().cloned());
+                }
+                wash_trade_config.funders.extend(
+                    indexer
+                        .wash_trade_analyzer()
+                        .funders()
+                        .iter()
+                        .map(|(account_id, funder_id)| (account_id.clone(), funder_id.clone())),
+                );
+                log::error!("Indexer run failed, restarting in {RESTART_DELAY:?}: {err:?}");
+                tokio::time::sleep(RESTART_DELAY).await;
+            }
+            Err(err) => panic!("Indexer run failed: {err:?}"),
+        }
+    }
 }
@@ -0,0 +1,253 @@
+//! Prometheus metrics, registered in the default registry and served by [`serve`]
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{InvalidLogReason, NftInvalidLog};
+
+/// Contracts beyond the first this many seen are counted under `other`, so
+/// that spam contracts can't blow up the number of time series
+const MAX_CONTRACT_LABELS: usize = 500;
+/// Requests with a longer request line and headers are rejected
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+/// Connections that don't send the request line and headers in this time are
+/// closed, so idle clients don't hold a task forever
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub static EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "nft_events_total",
+        "Events passed to the handler, trades are counted per token",
+        &["event", "contract_id"]
+    )
+    .unwrap()
+});
+
+pub static LOG_PARSE_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "nft_log_parse_failures_total",
+        "NEP-171 logs with bad JSON or an unknown event"
+    )
+    .unwrap()
+});
+
+pub static LOG_VALIDATION_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "nft_log_validation_failures_total",
+        "Logs of known events rejected because of their version (`wrong_version`) or data (`validation_failure`)",
+        &["event", "reason"]
+    )
+    .unwrap()
+});
+
+pub static REDIS_FLUSH_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "nft_redis_flush_duration_seconds",
+        "Time to flush a block to Redis, including retries. Atomic flushes are labeled `all`.",
+        &["stream"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0]
+    )
+    .unwrap()
+});
+
+pub static BLOCK_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("nft_block_height", "Height of the last processed block").unwrap()
+});
+
+pub static CHAIN_HEAD_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "nft_chain_head_height",
+        "Height of the latest block of the chain, see `poll_chain_head`"
+    )
+    .unwrap()
+});
+
+pub static BLOCK_LAG: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "nft_block_lag_blocks",
+        "Blocks between the chain head and the last processed block. Not set until the chain head is known."
+    )
+    .unwrap()
+});
+
+static CONTRACT_LABELS: LazyLock<Mutex<HashSet<AccountId>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+pub fn contract_label(contract_id: &AccountId) -> String {
+    let mut labels = CONTRACT_LABELS.lock().unwrap();
+    if labels.contains(contract_id) {
+        return contract_id.to_string();
+    }
+    if labels.len() < MAX_CONTRACT_LABELS {
+        labels.insert(contract_id.clone());
+        return contract_id.to_string();
+    }
+    "other".to_owned()
+}
+
+pub fn record_events(event: &str, contract_id: &AccountId, count: u64) {
+    if count > 0 {
+        EVENTS
+            .with_label_values(&[event, &contract_label(contract_id)])
+            .inc_by(count);
+    }
+}
+
+/// Counts a log rejected by [`parse_event_log`](crate::parse_event_log) in
+/// [`LOG_VALIDATION_FAILURES`] if it's a known event, or [`LOG_PARSE_FAILURES`]
+pub fn record_invalid_log(invalid_log: &NftInvalidLog) {
+    let reason = match invalid_log.reason {
+        InvalidLogReason::BadJson | InvalidLogReason::UnknownEvent => {
+            LOG_PARSE_FAILURES.inc();
+            return;
+        }
+        InvalidLogReason::WrongVersion => "wrong_version",
+        InvalidLogReason::ValidationFailure => "validation_failure",
+    };
+    let event = crate::nep171_event_name(&invalid_log.log).unwrap_or("unknown");
+    LOG_VALIDATION_FAILURES
+        .with_label_values(&[event, reason])
+        .inc();
+}
+
+/// Sets the last processed block and how far behind the chain head it is
+pub fn record_block_height(block_height: BlockHeight) {
+    BLOCK_HEIGHT.set(block_height as i64);
+    update_block_lag();
+}
+
+fn update_block_lag() {
+    let chain_head_height = CHAIN_HEAD_HEIGHT.get();
+    let block_height = BLOCK_HEIGHT.get();
+    if chain_head_height > 0 && block_height > 0 {
+        BLOCK_LAG.set(chain_head_height.saturating_sub(block_height).max(0));
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusResponse {
+    result: Status,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    sync_info: SyncInfo,
+}
+
+#[derive(Deserialize)]
+struct SyncInfo {
+    latest_block_height: BlockHeight,
+}
+
+/// Updates [`CHAIN_HEAD_HEIGHT`] from the `status` method of a NEAR RPC node
+/// every `interval`. Never returns.
+pub async fn poll_chain_head(rpc_url: String, interval: Duration) {
+    let client = reqwest::Client::new();
+    loop {
+        match chain_head_height(&client, &rpc_url).await {
+            Ok(chain_head_height) => {
+                CHAIN_HEAD_HEIGHT.set(chain_head_height as i64);
+                update_block_lag();
+            }
+            Err(err) => log::warn!("Failed to get the chain head from {rpc_url}: {err}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn chain_head_height(
+    client: &reqwest::Client,
+    rpc_url: &str,
+) -> Result<BlockHeight, reqwest::Error> {
+    let response: StatusResponse = client
+        .post(rpc_url)
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": "nft-indexer",
+            "method": "status",
+            "params": [],
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.result.sync_info.latest_block_height)
+}
+
+/// Serves `GET /metrics` until an error occurs on the listener
+pub async fn serve(addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving metrics on http://{addr}/metrics");
+    serve_listener(listener).await
+}
+
+/// Same as [`serve`], for a listener that is already bound
+pub async fn serve_listener(listener: TcpListener) -> std::io::Result<()> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = respond(&mut socket).await {
+                log::warn!("Failed to serve metrics: {err}");
+            }
+        });
+    }
+}
+
+/// Reads the request line and headers, which may arrive in several packets
+async fn read_request_head(socket: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Request head is too long",
+            ));
+        }
+        let len = socket.read(&mut buffer).await?;
+        if len == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buffer[..len]);
+    }
+    Ok(head)
+}
+
+async fn respond(socket: &mut TcpStream) -> std::io::Result<()> {
+    let request = tokio::time::timeout(REQUEST_HEAD_TIMEOUT, read_request_head(socket))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "Timed out reading request")
+        })??;
+    let response = if request.starts_with(b"GET /metrics ") {
+        let mut body = Vec::new();
+        let encoder = TextEncoder::new();
+        encoder
+            .encode(&prometheus::gather(), &mut body)
+            .map_err(std::io::Error::other)?;
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            encoder.format_type(),
+            body.len()
+        )
+        .into_bytes();
+        response.extend(body);
+        response
+    } else {
+        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+    };
+    socket.write_all(&response).await?;
+    socket.shutdown().await
+}
@@ -0,0 +1,616 @@
+//! Writes mints, transfers and burns to Parquet files for loading into DuckDB,
+//! Polars and the like. Meant for backfills of a
+//! [`BlockRange::Range`](inindexer::BlockRange::Range): rows are kept in memory
+//! until their day is over, so live indexing writes each day's files only after
+//! midnight UTC.
+//!
+//! Files are partitioned Hive-style, as
+//! `<table>/date=<YYYY-MM-DD>/contract=<contract>/<first block>-<last block>.parquet`,
+//! with one row per token. The partition key is `contract` rather than
+//! `contract_id`, so that it doesn't clash with the column of the same name.
+//! Amounts are decimal strings, since they can be larger than DECIMAL(38, 0).
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray, TimestampNanosecondArray,
+    UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_indexer_primitives::CryptoHash;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, PriceCurrency, TokenTrade,
+};
+
+const NANOSECONDS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Rows that are still in memory are written when the handler is dropped, so
+/// files only ever contain whole blocks. Rows of blocks within the block range
+/// of a file of their partition are skipped, so blocks that are processed again
+/// after a restart aren't written twice, while blocks below or between the
+/// files, like a backfill of an earlier range, get files of their own. Metadata
+/// updates, payout anomalies and invalid logs are not exported.
+pub struct PushToParquet {
+    directory: PathBuf,
+    max_rows_per_file: usize,
+    /// Day of the latest block with events, in days since the Unix epoch
+    current_day: Option<u64>,
+    mints: Table<MintRow>,
+    transfers: Table<TransferRow>,
+    burns: Table<BurnRow>,
+}
+
+impl PushToParquet {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            max_rows_per_file: 1_000_000,
+            current_day: None,
+            mints: Table::default(),
+            transfers: Table::default(),
+            burns: Table::default(),
+        }
+    }
+
+    /// A partition is written to a new file at the end of the block that makes
+    /// it at least this many rows, even if its day isn't over yet
+    pub fn with_max_rows_per_file(mut self, max_rows_per_file: usize) -> Self {
+        self.max_rows_per_file = max_rows_per_file;
+        self
+    }
+
+    /// Writes all rows that are still in memory
+    pub fn close(mut self) -> Result<(), ParquetError> {
+        self.write_all()
+    }
+
+    fn write_all(&mut self) -> Result<(), ParquetError> {
+        self.mints.write(&self.directory, |_, _| true)?;
+        self.transfers.write(&self.directory, |_, _| true)?;
+        self.burns.write(&self.directory, |_, _| true)?;
+        Ok(())
+    }
+}
+
+impl Drop for PushToParquet {
+    fn drop(&mut self) {
+        if let Err(err) = self.write_all() {
+            log::error!("Failed to write Parquet files: {err}");
+        }
+    }
+}
+
+/// Rows of one table, grouped by day and contract
+struct Table<R> {
+    /// Rows of the block that is being processed
+    pending: Vec<R>,
+    partitions: HashMap<(u64, AccountId), Vec<R>>,
+    /// First and last block of the files of each partition seen so far, read
+    /// from the file names the first time the partition is seen
+    written: HashMap<(u64, AccountId), Vec<(BlockHeight, BlockHeight)>>,
+}
+
+impl<R> Default for Table<R> {
+    fn default() -> Self {
+        Self {
+            pending: Vec::new(),
+            partitions: HashMap::new(),
+            written: HashMap::new(),
+        }
+    }
+}
+
+impl<R: Row> Table<R> {
+    /// Moves the rows of the block to their partitions, returns the latest day
+    /// among them
+    fn end_block(&mut self, directory: &Path) -> Result<Option<u64>, ParquetError> {
+        let mut latest_day = None;
+        for row in std::mem::take(&mut self.pending) {
+            let context = row.context();
+            let day = (context.block_timestamp_nanosec / NANOSECONDS_PER_DAY) as u64;
+            latest_day = latest_day.max(Some(day));
+            let key = (day, context.contract_id.clone());
+            if !self.written.contains_key(&key) {
+                let written = written_ranges::<R>(directory, day, &key.1)?;
+                self.written.insert(key.clone(), written);
+            }
+            // Blocks below or between the files are written, for backfills
+            if self.written[&key]
+                .iter()
+                .any(|(first, last)| (*first..=*last).contains(&context.block_height))
+            {
+                log::debug!(
+                    "Block {} is already in {} files of {}, skipping",
+                    context.block_height,
+                    R::TABLE,
+                    key.1
+                );
+                continue;
+            }
+            self.partitions.entry(key).or_default().push(row);
+        }
+        Ok(latest_day)
+    }
+
+    /// Writes and forgets the partitions for which `should_write` returns true
+    fn write(
+        &mut self,
+        directory: &Path,
+        should_write: impl Fn(u64, &[R]) -> bool,
+    ) -> Result<(), ParquetError> {
+        let keys = self
+            .partitions
+            .iter()
+            .filter(|((day, _), rows)| should_write(*day, rows))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            let rows = self.partitions.remove(&key).unwrap_or_default();
+            if let (Some(first), Some(last)) = (rows.first(), rows.last()) {
+                let range = (first.context().block_height, last.context().block_height);
+                write_file(directory, key.0, &key.1, &rows)?;
+                self.written.entry(key).or_default().push(range);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn partition_directory<R: Row>(directory: &Path, day: u64, contract_id: &AccountId) -> PathBuf {
+    directory
+        .join(R::TABLE)
+        .join(format!("date={}", date(day)))
+        .join(format!("contract={contract_id}"))
+}
+
+/// First and last block of the files already in the partition, from their names
+fn written_ranges<R: Row>(
+    directory: &Path,
+    day: u64,
+    contract_id: &AccountId,
+) -> io::Result<Vec<(BlockHeight, BlockHeight)>> {
+    let entries = match fs::read_dir(partition_directory::<R>(directory, day, contract_id)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut written = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let range = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".parquet"))
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(first, last)| {
+                Some((
+                    first.parse::<BlockHeight>().ok()?,
+                    last.parse::<BlockHeight>().ok()?,
+                ))
+            });
+        written.extend(range);
+    }
+    Ok(written)
+}
+
+fn write_file<R: Row>(
+    directory: &Path,
+    day: u64,
+    contract_id: &AccountId,
+    rows: &[R],
+) -> Result<(), ParquetError> {
+    let (Some(first), Some(last)) = (rows.first(), rows.last()) else {
+        return Ok(());
+    };
+    let partition = partition_directory::<R>(directory, day, contract_id);
+    fs::create_dir_all(&partition)?;
+    let path = partition.join(format!(
+        "{}-{}.parquet",
+        first.context().block_height,
+        last.context().block_height
+    ));
+
+    let mut fields = context_fields();
+    fields.extend(R::fields());
+    let schema = Arc::new(Schema::new(fields));
+    let mut columns = context_columns(rows);
+    columns.extend(R::columns(rows)?);
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(File::create(&path)?, schema, Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    log::info!("Wrote {} rows to {}", rows.len(), path.display());
+    Ok(())
+}
+
+/// `YYYY-MM-DD` of a day since the Unix epoch, in the proleptic Gregorian calendar
+fn date(day: u64) -> String {
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = day as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day_of_month:02}")
+}
+
+/// A row of a table, for one token of an event
+trait Row: Sized {
+    const TABLE: &'static str;
+
+    fn context(&self) -> &EventContext;
+
+    fn token_id(&self) -> &str;
+
+    /// Fields after the ones of [`EventContext`] and `token_id`
+    fn fields() -> Vec<Field>;
+
+    fn columns(rows: &[Self]) -> Result<Vec<ArrayRef>, ParquetError>;
+}
+
+fn context_fields() -> Vec<Field> {
+    vec![
+        Field::new("transaction_id", DataType::Utf8, false),
+        Field::new("receipt_id", DataType::Utf8, false),
+        Field::new("log_index", DataType::UInt32, false),
+        Field::new("event_index", DataType::UInt32, false),
+        Field::new("block_height", DataType::UInt64, false),
+        Field::new(
+            "block_timestamp",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("tx_sender_id", DataType::Utf8, false),
+        Field::new("contract_id", DataType::Utf8, false),
+        Field::new("spam_score", DataType::Float64, false),
+        Field::new("token_id", DataType::Utf8, false),
+    ]
+}
+
+fn context_columns<R: Row>(rows: &[R]) -> Vec<ArrayRef> {
+    let contexts = || rows.iter().map(Row::context);
+    vec![
+        Arc::new(StringArray::from_iter_values(
+            contexts().map(|context| context.transaction_id.to_string()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            contexts().map(|context| context.receipt_id.to_string()),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            contexts().map(|context| context.log_index as u32),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            contexts().map(|context| context.event_index as u32),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            contexts().map(|context| context.block_height),
+        )),
+        Arc::new(
+            TimestampNanosecondArray::from_iter_values(
+                contexts().map(|context| context.block_timestamp_nanosec as i64),
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            contexts().map(|context| context.tx_sender_id.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            contexts().map(|context| context.contract_id.as_str()),
+        )),
+        Arc::new(Float64Array::from_iter_values(
+            contexts().map(|context| context.spam_score),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(Row::token_id),
+        )),
+    ]
+}
+
+/// Decimal strings, since `u128` amounts don't always fit in DECIMAL(38, 0)
+fn amount_column(amounts: impl Iterator<Item = Option<u128>>) -> ArrayRef {
+    Arc::new(
+        amounts
+            .map(|amount| amount.map(|amount| amount.to_string()))
+            .collect::<StringArray>(),
+    )
+}
+
+fn optional_string_column<'a>(values: impl Iterator<Item = Option<&'a str>>) -> ArrayRef {
+    Arc::new(values.collect::<StringArray>())
+}
+
+struct MintRow {
+    context: EventContext,
+    token_id: String,
+    owner_id: AccountId,
+    memo: Option<String>,
+}
+
+impl Row for MintRow {
+    const TABLE: &'static str = "nft_mints";
+
+    fn context(&self) -> &EventContext {
+        &self.context
+    }
+
+    fn token_id(&self) -> &str {
+        &self.token_id
+    }
+
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::new("owner_id", DataType::Utf8, false),
+            Field::new("memo", DataType::Utf8, true),
+        ]
+    }
+
+    fn columns(rows: &[Self]) -> Result<Vec<ArrayRef>, ParquetError> {
+        Ok(vec![
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|row| row.owner_id.as_str()),
+            )),
+            optional_string_column(rows.iter().map(|row| row.memo.as_deref())),
+        ])
+    }
+}
+
+struct TransferRow {
+    context: EventContext,
+    token_id: String,
+    old_owner_id: AccountId,
+    new_owner_id: AccountId,
+    authorized_id: Option<AccountId>,
+    memo: Option<String>,
+    reverted_transfer_receipt_id: Option<CryptoHash>,
+    suspected_wash_trade: bool,
+    trade: Option<TokenTrade>,
+}
+
+impl Row for TransferRow {
+    const TABLE: &'static str = "nft_transfers";
+
+    fn context(&self) -> &EventContext {
+        &self.context
+    }
+
+    fn token_id(&self) -> &str {
+        &self.token_id
+    }
+
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::new("old_owner_id", DataType::Utf8, false),
+            Field::new("new_owner_id", DataType::Utf8, false),
+            Field::new("authorized_id", DataType::Utf8, true),
+            Field::new("memo", DataType::Utf8, true),
+            Field::new("reverted_transfer_receipt_id", DataType::Utf8, true),
+            Field::new("suspected_wash_trade", DataType::Boolean, false),
+            // Null if the transfer is not a trade
+            Field::new("price", DataType::Utf8, true),
+            Field::new("currency", DataType::Utf8, true),
+            Field::new("marketplace_id", DataType::Utf8, true),
+            Field::new("buyer_id", DataType::Utf8, true),
+            Field::new("seller_proceeds", DataType::Utf8, true),
+        ]
+    }
+
+    fn columns(rows: &[Self]) -> Result<Vec<ArrayRef>, ParquetError> {
+        let trades = || rows.iter().map(|row| row.trade.as_ref());
+        Ok(vec![
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|row| row.old_owner_id.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|row| row.new_owner_id.as_str()),
+            )),
+            optional_string_column(
+                rows.iter()
+                    .map(|row| row.authorized_id.as_ref().map(AccountId::as_str)),
+            ),
+            optional_string_column(rows.iter().map(|row| row.memo.as_deref())),
+            Arc::new(
+                rows.iter()
+                    .map(|row| {
+                        row.reverted_transfer_receipt_id
+                            .map(|receipt_id| receipt_id.to_string())
+                    })
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|row| Some(row.suspected_wash_trade)),
+            )),
+            amount_column(trades().map(|trade| trade.map(|trade| trade.price))),
+            optional_string_column(trades().map(|trade| {
+                trade.map(|trade| match &trade.currency {
+                    PriceCurrency::Near => "near",
+                    PriceCurrency::Ft(ft_contract_id) => ft_contract_id.as_str(),
+                })
+            })),
+            optional_string_column(
+                trades().map(|trade| trade.map(|trade| trade.marketplace_id.as_str())),
+            ),
+            optional_string_column(
+                trades().map(|trade| trade.map(|trade| trade.buyer_id.as_str())),
+            ),
+            amount_column(trades().map(|trade| {
+                trade
+                    .and_then(|trade| trade.payout.as_ref())
+                    .map(|payout| payout.seller_proceeds)
+            })),
+        ])
+    }
+}
+
+struct BurnRow {
+    context: EventContext,
+    token_id: String,
+    owner_id: AccountId,
+    authorized_id: Option<AccountId>,
+    memo: Option<String>,
+}
+
+impl Row for BurnRow {
+    const TABLE: &'static str = "nft_burns";
+
+    fn context(&self) -> &EventContext {
+        &self.context
+    }
+
+    fn token_id(&self) -> &str {
+        &self.token_id
+    }
+
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::new("owner_id", DataType::Utf8, false),
+            Field::new("authorized_id", DataType::Utf8, true),
+            Field::new("memo", DataType::Utf8, true),
+        ]
+    }
+
+    fn columns(rows: &[Self]) -> Result<Vec<ArrayRef>, ParquetError> {
+        Ok(vec![
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|row| row.owner_id.as_str()),
+            )),
+            optional_string_column(
+                rows.iter()
+                    .map(|row| row.authorized_id.as_ref().map(AccountId::as_str)),
+            ),
+            optional_string_column(rows.iter().map(|row| row.memo.as_deref())),
+        ])
+    }
+}
+
+#[async_trait]
+impl NftEventHandler for PushToParquet {
+    type Error = ParquetError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        for token_id in mint.event.token_ids {
+            self.mints.pending.push(MintRow {
+                context: context.clone(),
+                token_id,
+                owner_id: mint.event.owner_id.clone(),
+                memo: mint.event.memo.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        for (token_id, trade) in transfer
+            .event
+            .token_ids
+            .into_iter()
+            .zip(transfer.trade.token_trades)
+        {
+            self.transfers.pending.push(TransferRow {
+                context: context.clone(),
+                token_id,
+                old_owner_id: transfer.event.old_owner_id.clone(),
+                new_owner_id: transfer.event.new_owner_id.clone(),
+                authorized_id: transfer.event.authorized_id.clone(),
+                memo: transfer.event.memo.clone(),
+                reverted_transfer_receipt_id: transfer.reverted_transfer_receipt_id,
+                suspected_wash_trade: transfer.suspected_wash_trade,
+                trade,
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        for token_id in burn.event.token_ids {
+            self.burns.pending.push(BurnRow {
+                context: context.clone(),
+                token_id,
+                owner_id: burn.event.owner_id.clone(),
+                authorized_id: burn.event.authorized_id.clone(),
+                memo: burn.event.memo.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        _metadata_update: ExtendedNftMetadataUpdateEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        _contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Writes the partitions of days that are over, and the ones that reached
+    /// `max_rows_per_file`
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), Self::Error> {
+        let latest_day = [
+            self.mints.end_block(&self.directory)?,
+            self.transfers.end_block(&self.directory)?,
+            self.burns.end_block(&self.directory)?,
+        ]
+        .into_iter()
+        .max()
+        .flatten();
+        let current_day = self.current_day.max(latest_day);
+        self.current_day = current_day;
+
+        let max_rows_per_file = self.max_rows_per_file;
+        let should_write = |day: u64, rows: usize| {
+            current_day.is_some_and(|current_day| day < current_day) || rows >= max_rows_per_file
+        };
+        self.mints
+            .write(&self.directory, |day, rows| should_write(day, rows.len()))?;
+        self.transfers
+            .write(&self.directory, |day, rows| should_write(day, rows.len()))?;
+        self.burns
+            .write(&self.directory, |day, rows| should_write(day, rows.len()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+//! Writes mints, transfers, trades and burns to normalized PostgreSQL tables. The
+//! schema is in `migrations/postgres` and is applied by [`PushToPostgres::new`].
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{Balance, BlockHeight};
+use tokio_postgres::{Client, Error as PostgresError, NoTls, Transaction};
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, PriceCurrency,
+};
+
+/// Applied in order, each one at most once
+const MIGRATIONS: &[(&str, &str)] = &[(
+    "0001_create_tables",
+    include_str!("../migrations/postgres/0001_create_tables.sql"),
+)];
+
+pub struct PushToPostgres {
+    client: Client,
+    checkpoint_name: String,
+    mints: Vec<(ExtendedNftMintEvent, EventContext)>,
+    transfers: Vec<(ExtendedNftTransferEvent, EventContext)>,
+    burns: Vec<(ExtendedNftBurnEvent, EventContext)>,
+}
+
+impl PushToPostgres {
+    /// Applies the migrations that haven't been applied to the database yet
+    pub async fn new(mut client: Client) -> Result<Self, PostgresError> {
+        migrate(&mut client).await?;
+        Ok(Self {
+            client,
+            checkpoint_name: "nft-indexer".to_owned(),
+            mints: Vec::new(),
+            transfers: Vec::new(),
+            burns: Vec::new(),
+        })
+    }
+
+    /// Connects without TLS, for databases on the same host or private network
+    pub async fn connect(database_url: &str) -> Result<Self, PostgresError> {
+        let (client, connection) = tokio_postgres::connect(database_url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("Postgres connection closed: {err}");
+            }
+        });
+        Self::new(client).await
+    }
+
+    /// Row of `nft_checkpoints` that stores the last flushed block. Indexers that
+    /// write to the same database need different names.
+    pub fn with_checkpoint_name(mut self, checkpoint_name: impl Into<String>) -> Self {
+        self.checkpoint_name = checkpoint_name.into();
+        self
+    }
+
+    /// Highest block written to the database, if any, to resume from after a
+    /// restart. Blocks below it may be missing if they were never processed.
+    pub async fn last_block_height(&self) -> Result<Option<BlockHeight>, PostgresError> {
+        let row = self
+            .client
+            .query_opt(
+                "SELECT block_height FROM nft_checkpoints WHERE name = $1",
+                &[&self.checkpoint_name],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as BlockHeight))
+    }
+}
+
+async fn migrate(client: &mut Client) -> Result<(), PostgresError> {
+    let transaction = client.transaction().await?;
+    transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS nft_schema_migrations (
+                name TEXT PRIMARY KEY,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            LOCK TABLE nft_schema_migrations IN EXCLUSIVE MODE;",
+        )
+        .await?;
+    for (name, sql) in MIGRATIONS {
+        let applied = transaction
+            .query_opt(
+                "SELECT 1 FROM nft_schema_migrations WHERE name = $1",
+                &[name],
+            )
+            .await?
+            .is_some();
+        if !applied {
+            log::info!("Applying migration {name}");
+            transaction.batch_execute(sql).await?;
+            transaction
+                .execute(
+                    "INSERT INTO nft_schema_migrations (name) VALUES ($1)",
+                    &[name],
+                )
+                .await?;
+        }
+    }
+    transaction.commit().await
+}
+
+/// NUMERIC columns are passed as text and cast in SQL
+fn numeric(amount: Balance) -> String {
+    amount.to_string()
+}
+
+/// Columns shared by all event tables, one entry per token
+#[derive(Default)]
+struct EventColumns {
+    receipt_id: Vec<String>,
+    log_index: Vec<i32>,
+    event_index: Vec<i32>,
+    token_id: Vec<String>,
+    contract_id: Vec<String>,
+    transaction_id: Vec<String>,
+    block_height: Vec<i64>,
+    block_timestamp_nanosec: Vec<i64>,
+    spam_score: Vec<f64>,
+}
+
+impl EventColumns {
+    fn push(&mut self, token_id: &str, context: &EventContext) {
+        self.receipt_id.push(context.receipt_id.to_string());
+        self.log_index.push(context.log_index as i32);
+        self.event_index.push(context.event_index as i32);
+        self.token_id.push(token_id.to_owned());
+        self.contract_id.push(context.contract_id.to_string());
+        self.transaction_id.push(context.transaction_id.to_string());
+        self.block_height.push(context.block_height as i64);
+        self.block_timestamp_nanosec
+            .push(context.block_timestamp_nanosec as i64);
+        self.spam_score.push(context.spam_score);
+    }
+}
+
+async fn insert_mints(
+    transaction: &Transaction<'_>,
+    mints: &[(ExtendedNftMintEvent, EventContext)],
+) -> Result<(), PostgresError> {
+    let mut columns = EventColumns::default();
+    let mut owner_id = Vec::new();
+    let mut memo = Vec::new();
+    for (mint, context) in mints {
+        for token_id in &mint.event.token_ids {
+            columns.push(token_id, context);
+            owner_id.push(mint.event.owner_id.to_string());
+            memo.push(mint.event.memo.clone());
+        }
+    }
+    transaction
+        .execute(
+            "INSERT INTO nft_mints (receipt_id, log_index, event_index, token_id, contract_id,
+                transaction_id, block_height, block_timestamp_nanosec, spam_score, owner_id, memo)
+            SELECT * FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::INTEGER[], $4::TEXT[], $5::TEXT[],
+                $6::TEXT[], $7::BIGINT[], $8::BIGINT[], $9::DOUBLE PRECISION[], $10::TEXT[], $11::TEXT[])
+            ON CONFLICT DO NOTHING",
+            &[
+                &columns.receipt_id,
+                &columns.log_index,
+                &columns.event_index,
+                &columns.token_id,
+                &columns.contract_id,
+                &columns.transaction_id,
+                &columns.block_height,
+                &columns.block_timestamp_nanosec,
+                &columns.spam_score,
+                &owner_id,
+                &memo,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn insert_transfers(
+    transaction: &Transaction<'_>,
+    transfers: &[(ExtendedNftTransferEvent, EventContext)],
+) -> Result<(), PostgresError> {
+    let mut columns = EventColumns::default();
+    let mut old_owner_id = Vec::new();
+    let mut new_owner_id = Vec::new();
+    let mut authorized_id = Vec::new();
+    let mut memo = Vec::new();
+    let mut reverted_transfer_receipt_id = Vec::new();
+    let mut suspected_wash_trade = Vec::new();
+    for (transfer, context) in transfers {
+        for token_id in &transfer.event.token_ids {
+            columns.push(token_id, context);
+            old_owner_id.push(transfer.event.old_owner_id.to_string());
+            new_owner_id.push(transfer.event.new_owner_id.to_string());
+            authorized_id.push(
+                transfer
+                    .event
+                    .authorized_id
+                    .as_ref()
+                    .map(ToString::to_string),
+            );
+            memo.push(transfer.event.memo.clone());
+            reverted_transfer_receipt_id.push(
+                transfer
+                    .reverted_transfer_receipt_id
+                    .map(|receipt_id| receipt_id.to_string()),
+            );
+            suspected_wash_trade.push(transfer.suspected_wash_trade);
+        }
+    }
+    transaction
+        .execute(
+            "INSERT INTO nft_transfers (receipt_id, log_index, event_index, token_id, contract_id,
+                transaction_id, block_height, block_timestamp_nanosec, spam_score, old_owner_id,
+                new_owner_id, authorized_id, memo, reverted_transfer_receipt_id, suspected_wash_trade)
+            SELECT * FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::INTEGER[], $4::TEXT[], $5::TEXT[],
+                $6::TEXT[], $7::BIGINT[], $8::BIGINT[], $9::DOUBLE PRECISION[], $10::TEXT[],
+                $11::TEXT[], $12::TEXT[], $13::TEXT[], $14::TEXT[], $15::BOOLEAN[])
+            ON CONFLICT DO NOTHING",
+            &[
+                &columns.receipt_id,
+                &columns.log_index,
+                &columns.event_index,
+                &columns.token_id,
+                &columns.contract_id,
+                &columns.transaction_id,
+                &columns.block_height,
+                &columns.block_timestamp_nanosec,
+                &columns.spam_score,
+                &old_owner_id,
+                &new_owner_id,
+                &authorized_id,
+                &memo,
+                &reverted_transfer_receipt_id,
+                &suspected_wash_trade,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn insert_trades(
+    transaction: &Transaction<'_>,
+    transfers: &[(ExtendedNftTransferEvent, EventContext)],
+) -> Result<(), PostgresError> {
+    let mut receipt_id = Vec::new();
+    let mut log_index = Vec::new();
+    let mut event_index = Vec::new();
+    let mut token_id = Vec::new();
+    let mut price = Vec::new();
+    let mut currency = Vec::new();
+    let mut marketplace_id = Vec::new();
+    let mut buyer_id = Vec::new();
+    let mut approval_id = Vec::new();
+    let mut declared_balance = Vec::new();
+    let mut max_len_payout = Vec::new();
+    let mut seller_proceeds = Vec::new();
+    let mut marketplace_fee = Vec::new();
+    let mut royalty_receipt_id = Vec::new();
+    let mut royalty_log_index = Vec::new();
+    let mut royalty_event_index = Vec::new();
+    let mut royalty_token_id = Vec::new();
+    let mut royalty_account_id = Vec::new();
+    let mut royalty_amount = Vec::new();
+    for (transfer, context) in transfers {
+        for (traded_token_id, trade) in transfer
+            .event
+            .token_ids
+            .iter()
+            .zip(&transfer.trade.token_trades)
+        {
+            let Some(trade) = trade else {
+                continue;
+            };
+            receipt_id.push(context.receipt_id.to_string());
+            log_index.push(context.log_index as i32);
+            event_index.push(context.event_index as i32);
+            token_id.push(traded_token_id.clone());
+            price.push(numeric(trade.price));
+            currency.push(match &trade.currency {
+                PriceCurrency::Near => "near".to_owned(),
+                PriceCurrency::Ft(ft_contract_id) => ft_contract_id.to_string(),
+            });
+            marketplace_id.push(trade.marketplace_id.to_string());
+            buyer_id.push(trade.buyer_id.to_string());
+            approval_id.push(trade.approval_id.map(|approval_id| approval_id as i64));
+            declared_balance.push(numeric(trade.declared_balance));
+            max_len_payout.push(trade.max_len_payout.map(i64::from));
+            seller_proceeds.push(
+                trade
+                    .payout
+                    .as_ref()
+                    .map(|payout| numeric(payout.seller_proceeds)),
+            );
+            marketplace_fee.push(
+                trade
+                    .payout
+                    .as_ref()
+                    .and_then(|payout| payout.marketplace_fee)
+                    .map(numeric),
+            );
+            for (account_id, amount) in trade.payout.iter().flat_map(|payout| &payout.royalties) {
+                royalty_receipt_id.push(context.receipt_id.to_string());
+                royalty_log_index.push(context.log_index as i32);
+                royalty_event_index.push(context.event_index as i32);
+                royalty_token_id.push(traded_token_id.clone());
+                royalty_account_id.push(account_id.to_string());
+                royalty_amount.push(numeric(*amount));
+            }
+        }
+    }
+    transaction
+        .execute(
+            "INSERT INTO nft_trades (receipt_id, log_index, event_index, token_id, price, currency,
+                marketplace_id, buyer_id, approval_id, declared_balance, max_len_payout,
+                seller_proceeds, marketplace_fee)
+            SELECT * FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::INTEGER[], $4::TEXT[],
+                $5::TEXT[]::NUMERIC[], $6::TEXT[], $7::TEXT[], $8::TEXT[], $9::BIGINT[],
+                $10::TEXT[]::NUMERIC[], $11::BIGINT[], $12::TEXT[]::NUMERIC[], $13::TEXT[]::NUMERIC[])
+            ON CONFLICT DO NOTHING",
+            &[
+                &receipt_id,
+                &log_index,
+                &event_index,
+                &token_id,
+                &price,
+                &currency,
+                &marketplace_id,
+                &buyer_id,
+                &approval_id,
+                &declared_balance,
+                &max_len_payout,
+                &seller_proceeds,
+                &marketplace_fee,
+            ],
+        )
+        .await?;
+    transaction
+        .execute(
+            "INSERT INTO nft_trade_royalties (receipt_id, log_index, event_index, token_id,
+                account_id, amount)
+            SELECT * FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::INTEGER[], $4::TEXT[], $5::TEXT[],
+                $6::TEXT[]::NUMERIC[])
+            ON CONFLICT DO NOTHING",
+            &[
+                &royalty_receipt_id,
+                &royalty_log_index,
+                &royalty_event_index,
+                &royalty_token_id,
+                &royalty_account_id,
+                &royalty_amount,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn insert_burns(
+    transaction: &Transaction<'_>,
+    burns: &[(ExtendedNftBurnEvent, EventContext)],
+) -> Result<(), PostgresError> {
+    let mut columns = EventColumns::default();
+    let mut owner_id = Vec::new();
+    let mut authorized_id = Vec::new();
+    let mut memo = Vec::new();
+    for (burn, context) in burns {
+        for token_id in &burn.event.token_ids {
+            columns.push(token_id, context);
+            owner_id.push(burn.event.owner_id.to_string());
+            authorized_id.push(burn.event.authorized_id.as_ref().map(ToString::to_string));
+            memo.push(burn.event.memo.clone());
+        }
+    }
+    transaction
+        .execute(
+            "INSERT INTO nft_burns (receipt_id, log_index, event_index, token_id, contract_id,
+                transaction_id, block_height, block_timestamp_nanosec, spam_score, owner_id,
+                authorized_id, memo)
+            SELECT * FROM UNNEST($1::TEXT[], $2::INTEGER[], $3::INTEGER[], $4::TEXT[], $5::TEXT[],
+                $6::TEXT[], $7::BIGINT[], $8::BIGINT[], $9::DOUBLE PRECISION[], $10::TEXT[],
+                $11::TEXT[], $12::TEXT[])
+            ON CONFLICT DO NOTHING",
+            &[
+                &columns.receipt_id,
+                &columns.log_index,
+                &columns.event_index,
+                &columns.token_id,
+                &columns.contract_id,
+                &columns.transaction_id,
+                &columns.block_height,
+                &columns.block_timestamp_nanosec,
+                &columns.spam_score,
+                &owner_id,
+                &authorized_id,
+                &memo,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Metadata updates, payout anomalies and invalid logs are not stored
+#[async_trait]
+impl NftEventHandler for PushToPostgres {
+    type Error = PostgresError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.mints.push((mint, context));
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.transfers.push((transfer, context));
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.burns.push((burn, context));
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        _metadata_update: ExtendedNftMetadataUpdateEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        _contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Writes all events of the block and the checkpoint in one transaction. Rows
+    /// that are already there are left as they are, so re-processing a block after
+    /// a restart or backfilling blocks below the checkpoint doesn't write anything
+    /// twice. The checkpoint only moves forward.
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        let transaction = self.client.transaction().await?;
+        insert_mints(&transaction, &self.mints).await?;
+        insert_transfers(&transaction, &self.transfers).await?;
+        insert_trades(&transaction, &self.transfers).await?;
+        insert_burns(&transaction, &self.burns).await?;
+        transaction
+            .execute(
+                "INSERT INTO nft_checkpoints (name, block_height) VALUES ($1, $2)
+                ON CONFLICT (name) DO UPDATE
+                SET block_height = GREATEST(nft_checkpoints.block_height, EXCLUDED.block_height)",
+                &[&self.checkpoint_name, &(block_height as i64)],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        self.mints.clear();
+        self.transfers.clear();
+        self.burns.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Replays blocks saved to disk, so that tests don't depend on neardata being reachable.
+//! Blocks are captured with `cargo run --example capture_fixtures -- <start> <end> <dir>`.
+
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_indexer_primitives::StreamerMessage;
+use inindexer::MessageStreamer;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone)]
+pub struct RecordedBlockProvider {
+    dir: PathBuf,
+}
+
+impl RecordedBlockProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn load(
+        &self,
+        block_height: BlockHeight,
+    ) -> Result<Option<StreamerMessage>, RecordedBlockError> {
+        let path = block_path(&self.dir, block_height);
+        let contents =
+            std::fs::read(&path).map_err(|err| RecordedBlockError::Io(path.clone(), err))?;
+        serde_json::from_slice(&contents).map_err(|err| RecordedBlockError::Json(path, err))
+    }
+}
+
+/// Path of the fixture for `block_height`. The file contains `null` if there's no
+/// block at this height.
+pub fn block_path(dir: &Path, block_height: BlockHeight) -> PathBuf {
+    dir.join(format!("{block_height}.json"))
+}
+
+pub fn save_block(
+    dir: &Path,
+    block_height: BlockHeight,
+    block: Option<&StreamerMessage>,
+) -> Result<(), RecordedBlockError> {
+    let path = block_path(dir, block_height);
+    let contents =
+        serde_json::to_vec(&block).map_err(|err| RecordedBlockError::Json(path.clone(), err))?;
+    std::fs::write(&path, contents).map_err(|err| RecordedBlockError::Io(path, err))
+}
+
+#[derive(Debug)]
+pub enum RecordedBlockError {
+    Io(PathBuf, std::io::Error),
+    Json(PathBuf, serde_json::Error),
+    /// Recorded blocks can only be replayed for a range with an end
+    OpenEndedRange,
+}
+
+impl Display for RecordedBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordedBlockError::Io(path, err) => write!(
+                f,
+                "Failed to read {}, capture it with `cargo run --example capture_fixtures`: {err}",
+                path.display()
+            ),
+            RecordedBlockError::Json(path, err) => {
+                write!(f, "Invalid block in {}: {err}", path.display())
+            }
+            RecordedBlockError::OpenEndedRange => {
+                write!(f, "Recorded blocks can't be streamed without an end block")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordedBlockError {}
+
+#[async_trait]
+impl MessageStreamer for RecordedBlockProvider {
+    type Error = RecordedBlockError;
+
+    async fn stream(
+        self,
+        first_block_inclusive: BlockHeight,
+        last_block_exclusive: Option<BlockHeight>,
+    ) -> Result<
+        (
+            JoinHandle<Result<(), Self::Error>>,
+            mpsc::Receiver<StreamerMessage>,
+        ),
+        Self::Error,
+    > {
+        let last_block_exclusive =
+            last_block_exclusive.ok_or(RecordedBlockError::OpenEndedRange)?;
+        let (tx, rx) = mpsc::channel(100);
+        let handle = tokio::spawn(async move {
+            for block_height in first_block_inclusive..last_block_exclusive {
+                if let Some(block) = self.load(block_height)? {
+                    if tx.send(block).await.is_err() {
+                        // The indexer has stopped
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        });
+        Ok((handle, rx))
+    }
+}
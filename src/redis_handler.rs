@@ -1,89 +1,501 @@
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::time::Duration;
+
 use async_trait::async_trait;
 use inevents_redis::RedisEventStream;
 use inindexer::near_indexer_primitives::types::BlockHeight;
-use intear_events::events::nft::{
-    nft_burn::NftBurnEvent, nft_mint::NftMintEvent, nft_transfer::NftTransferEvent,
-};
+use rand::Rng;
 use redis::aio::ConnectionManager;
+use redis::streams::StreamRangeReply;
+use redis::{RedisError, Script};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
+use crate::stream_events::{
+    NftBurnEvent, NftContractMetadataUpdateEvent, NftInvalidLogEvent, NftMetadataUpdateEvent,
+    NftMintEvent, NftPayoutAnomalyEvent, NftTransferEvent,
+};
 use crate::{
-    EventContext, ExtendedNftBurnEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
-    NftEventHandler,
+    metrics, EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, NftInvalidLog, NftPayoutAnomaly,
 };
 
 pub struct PushToRedisStream {
-    mint_stream: RedisEventStream<NftMintEvent>,
-    transfer_stream: RedisEventStream<NftTransferEvent>,
-    burn_stream: RedisEventStream<NftBurnEvent>,
+    connection: ConnectionManager,
+    mint_stream: BufferedStream<NftMintEvent>,
+    transfer_stream: BufferedStream<NftTransferEvent>,
+    burn_stream: BufferedStream<NftBurnEvent>,
+    metadata_update_stream: BufferedStream<NftMetadataUpdateEvent>,
+    contract_metadata_update_stream: BufferedStream<NftContractMetadataUpdateEvent>,
+    payout_anomaly_stream: BufferedStream<NftPayoutAnomalyEvent>,
+    invalid_log_stream: BufferedStream<NftInvalidLogEvent>,
     max_stream_size: usize,
+    retry_policy: RetryPolicy,
+    flush_mode: FlushMode,
 }
 
 impl PushToRedisStream {
     pub async fn new(connection: ConnectionManager, max_stream_size: usize) -> Self {
         Self {
-            mint_stream: RedisEventStream::new(connection.clone(), "nft_mint"),
-            transfer_stream: RedisEventStream::new(connection.clone(), "nft_transfer"),
-            burn_stream: RedisEventStream::new(connection.clone(), "nft_burn"),
+            connection,
+            mint_stream: BufferedStream::new("nft_mint"),
+            transfer_stream: BufferedStream::new("nft_transfer"),
+            burn_stream: BufferedStream::new("nft_burn"),
+            metadata_update_stream: BufferedStream::new("nft_metadata_update"),
+            contract_metadata_update_stream: BufferedStream::new("nft_contract_metadata_update"),
+            payout_anomaly_stream: BufferedStream::new("nft_payout_anomaly"),
+            invalid_log_stream: BufferedStream::new("nft_invalid_log"),
             max_stream_size,
+            retry_policy: RetryPolicy::default(),
+            flush_mode: FlushMode::Sequential,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_flush_mode(mut self, flush_mode: FlushMode) -> Self {
+        self.flush_mode = flush_mode;
+        self
+    }
+
+    async fn flush_sequential(&mut self, block_height: BlockHeight) -> Result<(), RedisError> {
+        let connection = &self.connection;
+        let max_stream_size = self.max_stream_size;
+        let retry_policy = &self.retry_policy;
+        self.mint_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        self.transfer_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        self.burn_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        self.metadata_update_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        self.contract_metadata_update_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        self.payout_anomaly_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        self.invalid_log_stream
+            .flush(connection, block_height, max_stream_size, retry_policy)
+            .await?;
+        Ok(())
+    }
+
+    async fn flush_atomic(
+        &mut self,
+        block_height: BlockHeight,
+        last_block_key: &str,
+    ) -> Result<(), RedisError> {
+        let streams = [
+            self.mint_stream.serialize_events(),
+            self.transfer_stream.serialize_events(),
+            self.burn_stream.serialize_events(),
+            self.metadata_update_stream.serialize_events(),
+            self.contract_metadata_update_stream.serialize_events(),
+            self.payout_anomaly_stream.serialize_events(),
+            self.invalid_log_stream.serialize_events(),
+        ];
+        let script = Script::new(ATOMIC_FLUSH_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(last_block_key)
+            .arg(block_height)
+            .arg(self.max_stream_size);
+        for (name, events) in &streams {
+            invocation.key(*name).arg(events.len());
+            for event in events {
+                invocation.arg(event);
+            }
+        }
+
+        let connection = &self.connection;
+        let _timer = metrics::REDIS_FLUSH_DURATION
+            .with_label_values(&["all"])
+            .start_timer();
+        let written: bool = with_retry(
+            &self.retry_policy,
+            &format!("streams at block {block_height}"),
+            || {
+                let mut connection = connection.clone();
+                let invocation = &invocation;
+                async move { invocation.invoke_async(&mut connection).await }
+            },
+        )
+        .await?;
+        if !written {
+            log::warn!(
+                "Block {block_height} was already flushed according to {last_block_key}, skipping"
+            );
+        }
+
+        self.mint_stream.events.clear();
+        self.transfer_stream.events.clear();
+        self.burn_stream.events.clear();
+        self.metadata_update_stream.events.clear();
+        self.contract_metadata_update_stream.events.clear();
+        self.payout_anomaly_stream.events.clear();
+        self.invalid_log_stream.events.clear();
+        Ok(())
+    }
+}
+
+/// How the buffered events are written to Redis at the end of each block. In both
+/// modes, entry IDs are `<block height>-<index>`, the same as `RedisEventStream`
+/// writes them, and a stream whose last entry is from this block or a later one
+/// is skipped. That's what makes re-processing a block after a restart safe:
+/// streams that were written before the failure aren't pushed twice.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlushMode {
+    /// Each stream is flushed separately, one after another. A failure in the
+    /// middle leaves some streams a block ahead of the others until the block
+    /// is re-processed.
+    Sequential,
+    /// All streams and `last_block_key` are written by a single Lua script, so
+    /// no other client sees a block that is only written to some streams.
+    /// Blocks at or below the height stored in `last_block_key` are skipped.
+    ///
+    /// Redis doesn't roll back a script that fails halfway, so the script checks
+    /// every stream before writing anything. An error it can't check for, such as
+    /// running out of memory, can still leave a block partially written; the
+    /// block is then completed when it's re-processed.
+    ///
+    /// Only works if all streams and `last_block_key` are on the same node, so not
+    /// on Redis Cluster, where the script fails with `CROSSSLOT`. Use
+    /// [`FlushMode::Sequential`] there.
+    Atomic { last_block_key: String },
+}
+
+/// KEYS: last block key, then one key per stream.
+/// ARGV: block height, max stream size, then for each stream the number of
+/// events followed by the serialized events.
+const ATOMIC_FLUSH_SCRIPT: &str = r#"
+local block_height = tonumber(ARGV[1])
+local last_block = redis.call('GET', KEYS[1])
+if last_block and tonumber(last_block) >= block_height then
+    return 0
+end
+local max_stream_size = ARGV[2]
+local skip = {}
+for i = 2, #KEYS do
+    local last_entry = redis.call('XREVRANGE', KEYS[i], '+', '-', 'COUNT', 1)[1]
+    if last_entry then
+        local last_entry_block = tonumber(string.match(last_entry[1], '^(%d+)-'))
+        skip[i] = last_entry_block >= block_height
+    end
+end
+local arg = 3
+for i = 2, #KEYS do
+    local count = tonumber(ARGV[arg])
+    arg = arg + 1
+    for index = 0, count - 1 do
+        if not skip[i] then
+            redis.call('XADD', KEYS[i], 'MAXLEN', '~', max_stream_size, ARGV[1] .. '-' .. index, 'event', ARGV[arg])
+        end
+        arg = arg + 1
+    end
+end
+redis.call('SET', KEYS[1], block_height)
+return 1
+"#;
+
+/// Block height of the last entry of `stream`, from its `<block height>-<index>` ID
+async fn last_entry_block_height(
+    connection: &mut ConnectionManager,
+    stream: &str,
+) -> Result<Option<BlockHeight>, RedisError> {
+    let reply: StreamRangeReply = redis::cmd("XREVRANGE")
+        .arg(stream)
+        .arg("+")
+        .arg("-")
+        .arg("COUNT")
+        .arg(1)
+        .query_async(connection)
+        .await?;
+    Ok(reply.ids.first().and_then(|entry| {
+        let (block_height, _index) = entry.id.split_once('-')?;
+        block_height.parse().ok()
+    }))
+}
+
+async fn with_retry<T, F, Fut>(
+    retry_policy: &RetryPolicy,
+    description: &str,
+    mut f: F,
+) -> Result<T, RedisError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RedisError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < retry_policy.max_attempts => {
+                let delay = retry_policy.delay(attempt);
+                log::warn!(
+                    "Failed to flush {description} (attempt {attempt}/{}), retrying in {delay:?}: {err}",
+                    retry_policy.max_attempts,
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How flushing a stream is retried when Redis returns an error
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every subsequent retry
+    pub base_delay: Duration,
+    /// Upper bound for the delay between two attempts
+    pub max_delay: Duration,
+    /// Fraction of the delay that is randomized, from 0.0 (fixed delay) to 1.0
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retry() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before the `retry`-th retry (starting from 1), without jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let multiplier = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base_delay
+            .saturating_mul(multiplier)
+            .min(self.max_delay)
+    }
+
+    /// Checks the values that [`RetryPolicy::delay`] would otherwise have to guess
+    /// about, such as a NaN jitter
+    pub fn validate(&self) -> Result<(), InvalidRetryPolicy> {
+        if self.max_attempts == 0 {
+            return Err(InvalidRetryPolicy::NoAttempts);
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(InvalidRetryPolicy::Jitter(self.jitter));
+        }
+        Ok(())
+    }
+
+    /// Delay before the `retry`-th retry (starting from 1). Jitter only shortens
+    /// the delay, so it never exceeds `max_delay`. A jitter outside of 0.0..=1.0
+    /// is clamped, and a NaN one is treated as 0.0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        let jitter = if self.jitter.is_nan() {
+            0.0
+        } else {
+            self.jitter.clamp(0.0, 1.0)
+        };
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f64(1.0 - jitter * rand::thread_rng().gen_range(0.0..1.0))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidRetryPolicy {
+    /// `max_attempts` is 0, so nothing would ever be written
+    NoAttempts,
+    /// `jitter` is NaN or outside of 0.0..=1.0
+    Jitter(f64),
+}
+
+impl Display for InvalidRetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidRetryPolicy::NoAttempts => write!(f, "max_attempts must be at least 1"),
+            InvalidRetryPolicy::Jitter(jitter) => {
+                write!(f, "jitter must be between 0.0 and 1.0, got {jitter}")
+            }
         }
     }
 }
 
+impl std::error::Error for InvalidRetryPolicy {}
+
+/// Events of a single stream, kept until they're successfully flushed
+struct BufferedStream<E> {
+    name: &'static str,
+    events: Vec<E>,
+}
+
+impl<E: Serialize + DeserializeOwned + Clone + Send + Sync + 'static> BufferedStream<E> {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            events: Vec::new(),
+        }
+    }
+
+    fn add_event(&mut self, event: E) {
+        self.events.push(event);
+    }
+
+    fn serialize_events(&self) -> (&'static str, Vec<String>) {
+        let events = self
+            .events
+            .iter()
+            .map(|event| serde_json::to_string(event).expect("Failed to serialize event"))
+            .collect();
+        (self.name, events)
+    }
+
+    async fn flush(
+        &mut self,
+        connection: &ConnectionManager,
+        block_height: BlockHeight,
+        max_stream_size: usize,
+        retry_policy: &RetryPolicy,
+    ) -> Result<(), RedisError> {
+        let events = &self.events;
+        let name = self.name;
+        let _timer = metrics::REDIS_FLUSH_DURATION
+            .with_label_values(&[name])
+            .start_timer();
+        with_retry(
+            retry_policy,
+            &format!("{name} stream at block {block_height}"),
+            || {
+                let mut connection = connection.clone();
+                // A fresh RedisEventStream on every attempt, so that a failed attempt
+                // can't leave anything behind that would be pushed twice
+                let mut stream = RedisEventStream::new(connection.clone(), name);
+                for event in events.iter().cloned() {
+                    stream.add_event(event);
+                }
+                async move {
+                    if !events.is_empty()
+                        && last_entry_block_height(&mut connection, name)
+                            .await?
+                            .is_some_and(|last_block_height| last_block_height >= block_height)
+                    {
+                        log::warn!(
+                            "Block {block_height} is already in the {name} stream, skipping"
+                        );
+                        return Ok(());
+                    }
+                    stream.flush_events(block_height, max_stream_size).await
+                }
+            },
+        )
+        .await?;
+        self.events.clear();
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl NftEventHandler for PushToRedisStream {
-    async fn handle_mint(&mut self, mint: ExtendedNftMintEvent, context: EventContext) {
-        self.mint_stream.add_event(NftMintEvent {
-            owner_id: mint.event.owner_id,
-            token_ids: mint.event.token_ids,
-            memo: mint.event.memo,
-            transaction_id: context.transaction_id,
-            receipt_id: context.receipt_id,
-            block_height: context.block_height,
-            block_timestamp_nanosec: context.block_timestamp_nanosec,
-            contract_id: context.contract_id,
-        });
-    }
-
-    async fn handle_transfer(&mut self, transfer: ExtendedNftTransferEvent, context: EventContext) {
-        self.transfer_stream.add_event(NftTransferEvent {
-            old_owner_id: transfer.event.old_owner_id,
-            new_owner_id: transfer.event.new_owner_id,
-            token_ids: transfer.event.token_ids,
-            memo: transfer.event.memo,
-            token_prices_near: transfer.trade.token_prices_near,
-            transaction_id: context.transaction_id,
-            receipt_id: context.receipt_id,
-            block_height: context.block_height,
-            block_timestamp_nanosec: context.block_timestamp_nanosec,
-            contract_id: context.contract_id,
-        });
-    }
-
-    async fn handle_burn(&mut self, burn: ExtendedNftBurnEvent, context: EventContext) {
-        self.burn_stream.add_event(NftBurnEvent {
-            owner_id: burn.event.owner_id,
-            token_ids: burn.event.token_ids,
-            memo: burn.event.memo,
-            transaction_id: context.transaction_id,
-            receipt_id: context.receipt_id,
-            block_height: context.block_height,
-            block_timestamp_nanosec: context.block_timestamp_nanosec,
-            contract_id: context.contract_id,
-        });
-    }
-
-    async fn flush_events(&mut self, block_height: BlockHeight) {
-        self.mint_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush mint stream");
+    type Error = RedisError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.mint_stream.add_event(NftMintEvent::new(mint, context));
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
         self.transfer_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush transfer stream");
-        self.burn_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush burn stream");
+            .add_event(NftTransferEvent::new(transfer, context));
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.burn_stream.add_event(NftBurnEvent::new(burn, context));
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.metadata_update_stream
+            .add_event(NftMetadataUpdateEvent::new(metadata_update, context));
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.contract_metadata_update_stream
+            .add_event(NftContractMetadataUpdateEvent::new(
+                contract_metadata_update,
+                context,
+            ));
+        Ok(())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.payout_anomaly_stream
+            .add_event(NftPayoutAnomalyEvent::new(anomaly, context));
+        Ok(())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.invalid_log_stream
+            .add_event(NftInvalidLogEvent::new(invalid_log, context));
+        Ok(())
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        match self.flush_mode.clone() {
+            FlushMode::Sequential => self.flush_sequential(block_height).await,
+            FlushMode::Atomic { last_block_key } => {
+                self.flush_atomic(block_height, &last_block_key).await
+            }
+        }
     }
 }
@@ -0,0 +1,132 @@
+//! Heuristics for airdrop spam: contracts that mint tokens to thousands of accounts
+//! that never asked for them
+
+use std::collections::{HashMap, HashSet};
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use inindexer::near_indexer_primitives::CryptoHash;
+
+const MASS_MINT_WEIGHT: f64 = 0.5;
+const SUSPICIOUS_NAME_WEIGHT: f64 = 0.3;
+const NEVER_TRADED_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Clone)]
+pub struct SpamConfig {
+    /// A transaction that mints to at least this many distinct accounts other than
+    /// its signer is considered an unsolicited mass mint
+    pub mass_mint_threshold: usize,
+    /// Contracts whose account ID contains any of these are suspicious
+    pub suspicious_name_patterns: Vec<String>,
+    /// Events with a spam score at or above this are not passed to the handler
+    pub drop_threshold: Option<f64>,
+    /// Contracts that were traded before the indexer started, for example the ones
+    /// in the trades table of a database sink. Whether a contract was never traded
+    /// is only part of the score if this is set, since otherwise every contract
+    /// would look never traded after a restart.
+    pub traded_contracts: Option<HashSet<AccountId>>,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        Self {
+            mass_mint_threshold: 10,
+            suspicious_name_patterns: ["airdrop", "reward", "giveaway", "bonus", "voucher"]
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            drop_threshold: None,
+            traded_contracts: None,
+        }
+    }
+}
+
+impl SpamConfig {
+    pub fn should_drop(&self, spam_score: f64) -> bool {
+        self.drop_threshold
+            .is_some_and(|drop_threshold| spam_score >= drop_threshold)
+    }
+}
+
+/// Keeps track of what it has seen so far, so scores get more accurate the longer
+/// the indexer runs
+#[derive(Debug, Default)]
+pub struct SpamClassifier {
+    /// Contracts that had at least one trade since the classifier was created, on
+    /// top of [`SpamConfig::traded_contracts`]
+    traded_contracts: HashSet<AccountId>,
+    /// Distinct owners minted to by each transaction, other than the signer.
+    /// Cleared after each block.
+    unsolicited_owners: HashMap<(CryptoHash, AccountId), HashSet<AccountId>>,
+}
+
+impl SpamClassifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_mint(
+        &mut self,
+        transaction_id: CryptoHash,
+        contract_id: &AccountId,
+        signer_id: &AccountId,
+        owner_id: &AccountId,
+    ) {
+        if owner_id != signer_id {
+            self.unsolicited_owners
+                .entry((transaction_id, contract_id.clone()))
+                .or_default()
+                .insert(owner_id.clone());
+        }
+    }
+
+    pub fn record_trade(&mut self, contract_id: &AccountId) {
+        if !self.traded_contracts.contains(contract_id) {
+            self.traded_contracts.insert(contract_id.clone());
+        }
+    }
+
+    /// Contracts traded since the classifier was created, to carry them over to
+    /// [`SpamConfig::traded_contracts`] of the next one
+    pub fn traded_contracts(&self) -> &HashSet<AccountId> {
+        &self.traded_contracts
+    }
+
+    /// From 0.0 (nothing suspicious) to 1.0
+    pub fn score(
+        &self,
+        config: &SpamConfig,
+        transaction_id: CryptoHash,
+        contract_id: &AccountId,
+    ) -> f64 {
+        let mut score = 0.0;
+        if self
+            .unsolicited_owners
+            .get(&(transaction_id, contract_id.clone()))
+            .is_some_and(|owners| owners.len() >= config.mass_mint_threshold)
+        {
+            score += MASS_MINT_WEIGHT;
+        }
+        if config
+            .suspicious_name_patterns
+            .iter()
+            .any(|pattern| contract_id.as_str().contains(pattern.as_str()))
+        {
+            score += SUSPICIOUS_NAME_WEIGHT;
+        }
+        if config
+            .traded_contracts
+            .as_ref()
+            .is_some_and(|traded_contracts| {
+                !traded_contracts.contains(contract_id)
+                    && !self.traded_contracts.contains(contract_id)
+            })
+        {
+            score += NEVER_TRADED_WEIGHT;
+        }
+        score
+    }
+
+    pub fn end_block(&mut self) {
+        self.unsolicited_owners.clear();
+    }
+}
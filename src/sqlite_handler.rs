@@ -0,0 +1,469 @@
+//! Writes all events to a local SQLite database, along with the current owner of
+//! every token. The schema is in `migrations/sqlite` and is applied by
+//! [`PushToSqlite::new`].
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use rusqlite::{params, Connection, Error as SqliteError, OptionalExtension, TransactionBehavior};
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    InvalidLogReason, NftEventHandler, NftInvalidLog, NftPayoutAnomaly, PayoutAnomalyKind,
+    PriceCurrency,
+};
+
+/// Applied in order, `PRAGMA user_version` is the number of migrations applied
+const MIGRATIONS: &[&str] = &[include_str!("../migrations/sqlite/0001_create_tables.sql")];
+
+/// Events are written as they arrive, in a transaction that is committed by
+/// [`NftEventHandler::flush_events`]. If a call returns an error, the handler
+/// should be dropped, which rolls back the events of the unfinished block.
+///
+/// Blocks at or below the checkpoint, when re-processing after a restart or
+/// backfilling, are committed too, skipping rows that already exist. They don't
+/// change `nft_owners` or move the checkpoint back, since newer blocks have
+/// already been applied to both.
+pub struct PushToSqlite {
+    /// Only used through `&mut self`, the mutex makes the handler `Sync`
+    connection: Mutex<Connection>,
+    checkpoint_name: String,
+    /// Checkpoint when the transaction of the current block began
+    checkpoint: Option<BlockHeight>,
+}
+
+impl PushToSqlite {
+    /// Applies the migrations that haven't been applied to the database yet
+    pub fn new(mut connection: Connection) -> Result<Self, SqliteError> {
+        migrate(&mut connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            checkpoint_name: "nft-indexer".to_owned(),
+            checkpoint: None,
+        })
+    }
+
+    /// Creates the database file if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SqliteError> {
+        let connection = Connection::open(path)?;
+        // So that reading the database doesn't block the indexer
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        Self::new(connection)
+    }
+
+    /// Row of `nft_checkpoints` that stores the last flushed block
+    pub fn with_checkpoint_name(mut self, checkpoint_name: impl Into<String>) -> Self {
+        self.checkpoint_name = checkpoint_name.into();
+        self
+    }
+
+    /// Last block written to the database, if any
+    pub fn last_block_height(&self) -> Result<Option<BlockHeight>, SqliteError> {
+        last_block_height(&self.connection.lock().unwrap(), &self.checkpoint_name)
+    }
+
+    /// Begins the transaction of the current block if it's the first event of it.
+    /// Also returns whether the block is above the checkpoint, so it should update
+    /// `nft_owners` and the checkpoint.
+    fn transaction(
+        &mut self,
+        block_height: BlockHeight,
+    ) -> Result<(&Connection, bool), SqliteError> {
+        let connection = self.connection.get_mut().unwrap();
+        if connection.is_autocommit() {
+            connection.execute_batch("BEGIN")?;
+            self.checkpoint = last_block_height(connection, &self.checkpoint_name)?;
+        }
+        let is_new_block = !self
+            .checkpoint
+            .is_some_and(|checkpoint| checkpoint >= block_height);
+        Ok((connection, is_new_block))
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), SqliteError> {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    let applied: usize = transaction.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(applied) {
+        log::info!("Applying SQLite migration {}", index + 1);
+        transaction.execute_batch(sql)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+    }
+    transaction.commit()
+}
+
+fn last_block_height(
+    connection: &Connection,
+    checkpoint_name: &str,
+) -> Result<Option<BlockHeight>, SqliteError> {
+    let block_height = connection
+        .query_row(
+            "SELECT block_height FROM nft_checkpoints WHERE name = ?1",
+            params![checkpoint_name],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    Ok(block_height.map(|block_height| block_height as BlockHeight))
+}
+
+fn set_owner(
+    connection: &Connection,
+    context: &EventContext,
+    token_id: &str,
+    owner_id: &AccountId,
+) -> Result<(), SqliteError> {
+    connection
+        .prepare_cached(
+            "INSERT INTO nft_owners (contract_id, token_id, owner_id, block_height)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (contract_id, token_id) DO UPDATE
+            SET owner_id = excluded.owner_id, block_height = excluded.block_height",
+        )?
+        .execute(params![
+            context.contract_id.as_str(),
+            token_id,
+            owner_id.as_str(),
+            context.block_height as i64,
+        ])?;
+    Ok(())
+}
+
+fn remove_owner(
+    connection: &Connection,
+    context: &EventContext,
+    token_id: &str,
+) -> Result<(), SqliteError> {
+    connection
+        .prepare_cached("DELETE FROM nft_owners WHERE contract_id = ?1 AND token_id = ?2")?
+        .execute(params![context.contract_id.as_str(), token_id])?;
+    Ok(())
+}
+
+fn currency(currency: &PriceCurrency) -> &str {
+    match currency {
+        PriceCurrency::Near => "near",
+        PriceCurrency::Ft(ft_contract_id) => ft_contract_id.as_str(),
+    }
+}
+
+fn anomaly_kind(kind: PayoutAnomalyKind) -> &'static str {
+    match kind {
+        PayoutAnomalyKind::SumMismatch => "sum_mismatch",
+        PayoutAnomalyKind::TooManyRecipients => "too_many_recipients",
+    }
+}
+
+fn invalid_log_reason(reason: InvalidLogReason) -> &'static str {
+    match reason {
+        InvalidLogReason::BadJson => "bad_json",
+        InvalidLogReason::UnknownEvent => "unknown_event",
+        InvalidLogReason::WrongVersion => "wrong_version",
+        InvalidLogReason::ValidationFailure => "validation_failure",
+    }
+}
+
+#[async_trait]
+impl NftEventHandler for PushToSqlite {
+    type Error = SqliteError;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, is_new_block) = self.transaction(context.block_height)?;
+        for token_id in &mint.event.token_ids {
+            connection
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO nft_mints (receipt_id, log_index, event_index,
+                        token_id, contract_id, owner_id, memo, transaction_id, block_height,
+                        block_timestamp_nanosec, spam_score)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                )?
+                .execute(params![
+                    context.receipt_id.to_string(),
+                    context.log_index as i64,
+                    context.event_index as i64,
+                    token_id,
+                    context.contract_id.as_str(),
+                    mint.event.owner_id.as_str(),
+                    mint.event.memo,
+                    context.transaction_id.to_string(),
+                    context.block_height as i64,
+                    context.block_timestamp_nanosec as i64,
+                    context.spam_score,
+                ])?;
+            if is_new_block {
+                set_owner(connection, &context, token_id, &mint.event.owner_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, is_new_block) = self.transaction(context.block_height)?;
+        for (token_id, trade) in transfer
+            .event
+            .token_ids
+            .iter()
+            .zip(&transfer.trade.token_trades)
+        {
+            connection
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO nft_transfers (receipt_id, log_index, event_index,
+                        token_id, contract_id, old_owner_id, new_owner_id, authorized_id, memo,
+                        reverted_transfer_receipt_id, suspected_wash_trade, transaction_id,
+                        block_height, block_timestamp_nanosec, spam_score)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                )?
+                .execute(params![
+                    context.receipt_id.to_string(),
+                    context.log_index as i64,
+                    context.event_index as i64,
+                    token_id,
+                    context.contract_id.as_str(),
+                    transfer.event.old_owner_id.as_str(),
+                    transfer.event.new_owner_id.as_str(),
+                    transfer.event.authorized_id.as_ref().map(AccountId::as_str),
+                    transfer.event.memo,
+                    transfer
+                        .reverted_transfer_receipt_id
+                        .map(|receipt_id| receipt_id.to_string()),
+                    transfer.suspected_wash_trade,
+                    context.transaction_id.to_string(),
+                    context.block_height as i64,
+                    context.block_timestamp_nanosec as i64,
+                    context.spam_score,
+                ])?;
+            if is_new_block {
+                set_owner(connection, &context, token_id, &transfer.event.new_owner_id)?;
+            }
+
+            let Some(trade) = trade else {
+                continue;
+            };
+            connection
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO nft_trades (receipt_id, log_index, event_index,
+                        token_id, price, currency, marketplace_id, buyer_id, approval_id,
+                        declared_balance, max_len_payout, seller_proceeds, marketplace_fee)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                )?
+                .execute(params![
+                    context.receipt_id.to_string(),
+                    context.log_index as i64,
+                    context.event_index as i64,
+                    token_id,
+                    trade.price.to_string(),
+                    currency(&trade.currency),
+                    trade.marketplace_id.as_str(),
+                    trade.buyer_id.as_str(),
+                    trade.approval_id.map(|approval_id| approval_id as i64),
+                    trade.declared_balance.to_string(),
+                    trade.max_len_payout,
+                    trade
+                        .payout
+                        .as_ref()
+                        .map(|payout| payout.seller_proceeds.to_string()),
+                    trade
+                        .payout
+                        .as_ref()
+                        .and_then(|payout| payout.marketplace_fee)
+                        .map(|marketplace_fee| marketplace_fee.to_string()),
+                ])?;
+            for (account_id, amount) in trade.payout.iter().flat_map(|payout| &payout.royalties) {
+                connection
+                    .prepare_cached(
+                        "INSERT OR IGNORE INTO nft_trade_royalties (receipt_id, log_index,
+                            event_index, token_id, account_id, amount)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    )?
+                    .execute(params![
+                        context.receipt_id.to_string(),
+                        context.log_index as i64,
+                        context.event_index as i64,
+                        token_id,
+                        account_id.as_str(),
+                        amount.to_string(),
+                    ])?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, is_new_block) = self.transaction(context.block_height)?;
+        for token_id in &burn.event.token_ids {
+            connection
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO nft_burns (receipt_id, log_index, event_index,
+                        token_id, contract_id, owner_id, authorized_id, memo, transaction_id,
+                        block_height, block_timestamp_nanosec, spam_score)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                )?
+                .execute(params![
+                    context.receipt_id.to_string(),
+                    context.log_index as i64,
+                    context.event_index as i64,
+                    token_id,
+                    context.contract_id.as_str(),
+                    burn.event.owner_id.as_str(),
+                    burn.event.authorized_id.as_ref().map(AccountId::as_str),
+                    burn.event.memo,
+                    context.transaction_id.to_string(),
+                    context.block_height as i64,
+                    context.block_timestamp_nanosec as i64,
+                    context.spam_score,
+                ])?;
+            if is_new_block {
+                remove_owner(connection, &context, token_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, _) = self.transaction(context.block_height)?;
+        for token_id in &metadata_update.event.token_ids {
+            connection
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO nft_metadata_updates (receipt_id, log_index,
+                        event_index, token_id, contract_id, memo, transaction_id, block_height,
+                        block_timestamp_nanosec, spam_score)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                )?
+                .execute(params![
+                    context.receipt_id.to_string(),
+                    context.log_index as i64,
+                    context.event_index as i64,
+                    token_id,
+                    context.contract_id.as_str(),
+                    metadata_update.event.memo,
+                    context.transaction_id.to_string(),
+                    context.block_height as i64,
+                    context.block_timestamp_nanosec as i64,
+                    context.spam_score,
+                ])?;
+        }
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, _) = self.transaction(context.block_height)?;
+        connection
+            .prepare_cached(
+                "INSERT OR IGNORE INTO nft_contract_metadata_updates (receipt_id, log_index,
+                    event_index, contract_id, memo, transaction_id, block_height,
+                    block_timestamp_nanosec, spam_score)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute(params![
+                context.receipt_id.to_string(),
+                context.log_index as i64,
+                context.event_index as i64,
+                context.contract_id.as_str(),
+                contract_metadata_update.event.memo,
+                context.transaction_id.to_string(),
+                context.block_height as i64,
+                context.block_timestamp_nanosec as i64,
+                context.spam_score,
+            ])?;
+        Ok(())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, _) = self.transaction(context.block_height)?;
+        connection
+            .prepare_cached(
+                "INSERT OR IGNORE INTO nft_payout_anomalies (receipt_id, log_index, event_index,
+                    token_id, kind, contract_id, marketplace_id, declared_balance, payout_sum,
+                    max_len_payout, payout_len, transaction_id, block_height,
+                    block_timestamp_nanosec)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            )?
+            .execute(params![
+                context.receipt_id.to_string(),
+                context.log_index as i64,
+                context.event_index as i64,
+                anomaly.token_id,
+                anomaly_kind(anomaly.kind),
+                context.contract_id.as_str(),
+                anomaly.marketplace_id.as_str(),
+                anomaly.declared_balance.to_string(),
+                anomaly.payout_sum.to_string(),
+                anomaly.max_len_payout,
+                anomaly.payout_len as i64,
+                context.transaction_id.to_string(),
+                context.block_height as i64,
+                context.block_timestamp_nanosec as i64,
+            ])?;
+        Ok(())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        let (connection, _) = self.transaction(context.block_height)?;
+        connection
+            .prepare_cached(
+                "INSERT OR IGNORE INTO nft_invalid_logs (receipt_id, log_index, contract_id, log,
+                    reason, transaction_id, block_height, block_timestamp_nanosec)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                context.receipt_id.to_string(),
+                context.log_index as i64,
+                context.contract_id.as_str(),
+                invalid_log.log,
+                invalid_log_reason(invalid_log.reason),
+                context.transaction_id.to_string(),
+                context.block_height as i64,
+                context.block_timestamp_nanosec as i64,
+            ])?;
+        Ok(())
+    }
+
+    /// Commits the events of the block together with the checkpoint. Blocks at or
+    /// below the checkpoint are committed without moving it back.
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        let checkpoint_name = self.checkpoint_name.clone();
+        let (connection, is_new_block) = self.transaction(block_height)?;
+        if is_new_block {
+            connection.execute(
+                "INSERT INTO nft_checkpoints (name, block_height) VALUES (?1, ?2)
+                ON CONFLICT (name) DO UPDATE SET block_height = excluded.block_height",
+                params![checkpoint_name, block_height as i64],
+            )?;
+        } else {
+            log::info!(
+                "Block {block_height} is not above checkpoint {checkpoint_name}, keeping nft_owners and the checkpoint"
+            );
+        }
+        connection.execute_batch("COMMIT")
+    }
+}
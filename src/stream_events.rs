@@ -0,0 +1,278 @@
+//! Payloads for the Redis streams and Kafka topics. Mints and burns are the
+//! `intear_events` types, and transfers have the same fields as theirs, plus the
+//! ones added by this indexer.
+
+use inindexer::near_indexer_primitives::types::{AccountId, Balance, BlockHeight};
+use inindexer::near_indexer_primitives::CryptoHash;
+use inindexer::near_utils::dec_format;
+use intear_events::events::nft::{nft_burn, nft_mint};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    InvalidLogReason, NftInvalidLog, NftPayoutAnomaly, PayoutAnomalyKind, TokenTrade,
+};
+
+/// `intear_events`' `NftMintEvent`, with the fields added by this indexer
+pub type NftMintEvent = WithIndexerFields<nft_mint::NftMintEvent>;
+/// `intear_events`' `NftBurnEvent`, with the fields added by this indexer
+pub type NftBurnEvent = WithIndexerFields<nft_burn::NftBurnEvent>;
+
+/// An `intear_events` event with the fields added by this indexer next to its
+/// own, so consumers that only know `intear_events` can still read the payload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WithIndexerFields<E> {
+    #[serde(flatten)]
+    pub event: E,
+    pub receipt_index: usize,
+    pub log_index: usize,
+    pub event_index: usize,
+    pub spam_score: f64,
+}
+
+impl NftMintEvent {
+    pub fn new(mint: ExtendedNftMintEvent, context: EventContext) -> Self {
+        Self {
+            event: nft_mint::NftMintEvent {
+                owner_id: mint.event.owner_id,
+                token_ids: mint.event.token_ids,
+                memo: mint.event.memo,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                contract_id: context.contract_id,
+            },
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            spam_score: context.spam_score,
+        }
+    }
+}
+
+impl NftBurnEvent {
+    pub fn new(burn: ExtendedNftBurnEvent, context: EventContext) -> Self {
+        Self {
+            event: nft_burn::NftBurnEvent {
+                owner_id: burn.event.owner_id,
+                token_ids: burn.event.token_ids,
+                memo: burn.event.memo,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                contract_id: context.contract_id,
+            },
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            spam_score: context.spam_score,
+        }
+    }
+}
+
+/// Same as `intear_events`' `NftTransferEvent`, with additional trade details
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftTransferEvent {
+    pub old_owner_id: AccountId,
+    pub new_owner_id: AccountId,
+    pub token_ids: Vec<String>,
+    pub memo: Option<String>,
+    /// Only trades settled in NEAR, see `token_trades` for all trades
+    #[serde(with = "crate::dec_format_vec")]
+    pub token_prices_near: Vec<Option<Balance>>,
+    pub token_trades: Vec<Option<TokenTrade>>,
+    /// Receipt of the `nft_transfer_call` transfer that this one reverts, if any
+    pub reverted_transfer_receipt_id: Option<CryptoHash>,
+    pub suspected_wash_trade: bool,
+
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+    pub receipt_index: usize,
+    pub log_index: usize,
+    pub event_index: usize,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub block_timestamp_nanosec: u128,
+    pub contract_id: AccountId,
+    pub spam_score: f64,
+}
+
+impl NftTransferEvent {
+    pub fn new(transfer: ExtendedNftTransferEvent, context: EventContext) -> Self {
+        Self {
+            old_owner_id: transfer.event.old_owner_id,
+            new_owner_id: transfer.event.new_owner_id,
+            token_ids: transfer.event.token_ids,
+            memo: transfer.event.memo,
+            token_prices_near: transfer.trade.token_prices_near,
+            token_trades: transfer.trade.token_trades,
+            reverted_transfer_receipt_id: transfer.reverted_transfer_receipt_id,
+            suspected_wash_trade: transfer.suspected_wash_trade,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            contract_id: context.contract_id,
+            spam_score: context.spam_score,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftMetadataUpdateEvent {
+    pub token_ids: Vec<String>,
+    pub memo: Option<String>,
+
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+    pub receipt_index: usize,
+    pub log_index: usize,
+    pub event_index: usize,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub block_timestamp_nanosec: u128,
+    pub contract_id: AccountId,
+    pub spam_score: f64,
+}
+
+impl NftMetadataUpdateEvent {
+    pub fn new(metadata_update: ExtendedNftMetadataUpdateEvent, context: EventContext) -> Self {
+        Self {
+            token_ids: metadata_update.event.token_ids,
+            memo: metadata_update.event.memo,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            contract_id: context.contract_id,
+            spam_score: context.spam_score,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftContractMetadataUpdateEvent {
+    pub memo: Option<String>,
+
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+    pub receipt_index: usize,
+    pub log_index: usize,
+    pub event_index: usize,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub block_timestamp_nanosec: u128,
+    pub contract_id: AccountId,
+    pub spam_score: f64,
+}
+
+impl NftContractMetadataUpdateEvent {
+    pub fn new(
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Self {
+        Self {
+            memo: contract_metadata_update.event.memo,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            contract_id: context.contract_id,
+            spam_score: context.spam_score,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftPayoutAnomalyEvent {
+    pub token_id: String,
+    pub marketplace_id: AccountId,
+    pub kind: PayoutAnomalyKind,
+    #[serde(with = "dec_format")]
+    pub declared_balance: Balance,
+    #[serde(with = "dec_format")]
+    pub payout_sum: Balance,
+    pub max_len_payout: Option<u32>,
+    pub payout_len: usize,
+
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+    pub receipt_index: usize,
+    pub log_index: usize,
+    pub event_index: usize,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub block_timestamp_nanosec: u128,
+    pub contract_id: AccountId,
+    pub spam_score: f64,
+}
+
+impl NftPayoutAnomalyEvent {
+    pub fn new(anomaly: NftPayoutAnomaly, context: EventContext) -> Self {
+        Self {
+            token_id: anomaly.token_id,
+            marketplace_id: anomaly.marketplace_id,
+            kind: anomaly.kind,
+            declared_balance: anomaly.declared_balance,
+            payout_sum: anomaly.payout_sum,
+            max_len_payout: anomaly.max_len_payout,
+            payout_len: anomaly.payout_len,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            contract_id: context.contract_id,
+            spam_score: context.spam_score,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NftInvalidLogEvent {
+    pub log: String,
+    pub reason: InvalidLogReason,
+
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+    pub receipt_index: usize,
+    pub log_index: usize,
+    pub event_index: usize,
+    pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
+    pub block_timestamp_nanosec: u128,
+    pub contract_id: AccountId,
+    pub spam_score: f64,
+}
+
+impl NftInvalidLogEvent {
+    pub fn new(invalid_log: NftInvalidLog, context: EventContext) -> Self {
+        Self {
+            log: invalid_log.log,
+            reason: invalid_log.reason,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+            receipt_index: context.receipt_index,
+            log_index: context.log_index,
+            event_index: context.event_index,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            contract_id: context.contract_id,
+            spam_score: context.spam_score,
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! Helpers for testing code built on [`NftEventHandler`]
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+
+use crate::{
+    EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    NftEventHandler, NftInvalidLog, NftPayoutAnomaly,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum RecordedEvent {
+    Mint(ExtendedNftMintEvent, EventContext),
+    Transfer(ExtendedNftTransferEvent, EventContext),
+    Burn(ExtendedNftBurnEvent, EventContext),
+    MetadataUpdate(ExtendedNftMetadataUpdateEvent, EventContext),
+    ContractMetadataUpdate(ExtendedNftContractMetadataUpdateEvent, EventContext),
+    PayoutAnomaly(NftPayoutAnomaly, EventContext),
+    InvalidLog(NftInvalidLog, EventContext),
+    Flush(BlockHeight),
+}
+
+impl RecordedEvent {
+    /// None for [`RecordedEvent::Flush`]
+    pub fn context(&self) -> Option<&EventContext> {
+        match self {
+            RecordedEvent::Mint(_, context)
+            | RecordedEvent::Transfer(_, context)
+            | RecordedEvent::Burn(_, context)
+            | RecordedEvent::MetadataUpdate(_, context)
+            | RecordedEvent::ContractMetadataUpdate(_, context)
+            | RecordedEvent::PayoutAnomaly(_, context)
+            | RecordedEvent::InvalidLog(_, context) => Some(context),
+            RecordedEvent::Flush(_) => None,
+        }
+    }
+
+    pub fn block_height(&self) -> BlockHeight {
+        match self {
+            RecordedEvent::Flush(block_height) => *block_height,
+            _ => self.context().unwrap().block_height,
+        }
+    }
+
+    /// Tokens affected by this event, empty for events that aren't about specific tokens
+    pub fn token_ids(&self) -> Vec<&str> {
+        let token_ids: &[String] = match self {
+            RecordedEvent::Mint(mint, _) => &mint.event.token_ids,
+            RecordedEvent::Transfer(transfer, _) => &transfer.event.token_ids,
+            RecordedEvent::Burn(burn, _) => &burn.event.token_ids,
+            RecordedEvent::MetadataUpdate(metadata_update, _) => &metadata_update.event.token_ids,
+            RecordedEvent::PayoutAnomaly(anomaly, _) => std::slice::from_ref(&anomaly.token_id),
+            RecordedEvent::ContractMetadataUpdate(_, _)
+            | RecordedEvent::InvalidLog(_, _)
+            | RecordedEvent::Flush(_) => &[],
+        };
+        token_ids.iter().map(String::as_str).collect()
+    }
+}
+
+/// Records every call, in order
+#[derive(Debug, Default)]
+pub struct RecordingHandler {
+    pub events: Vec<RecordedEvent>,
+}
+
+impl RecordingHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mints(&self) -> Vec<(&ExtendedNftMintEvent, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::Mint(mint, context) => Some((mint, context)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn transfers(&self) -> Vec<(&ExtendedNftTransferEvent, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::Transfer(transfer, context) => Some((transfer, context)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn burns(&self) -> Vec<(&ExtendedNftBurnEvent, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::Burn(burn, context) => Some((burn, context)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn metadata_updates(&self) -> Vec<(&ExtendedNftMetadataUpdateEvent, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::MetadataUpdate(metadata_update, context) => {
+                    Some((metadata_update, context))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn contract_metadata_updates(
+        &self,
+    ) -> Vec<(&ExtendedNftContractMetadataUpdateEvent, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::ContractMetadataUpdate(contract_metadata_update, context) => {
+                    Some((contract_metadata_update, context))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn payout_anomalies(&self) -> Vec<(&NftPayoutAnomaly, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::PayoutAnomaly(anomaly, context) => Some((anomaly, context)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn invalid_logs(&self) -> Vec<(&NftInvalidLog, &EventContext)> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::InvalidLog(invalid_log, context) => Some((invalid_log, context)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn flushed_blocks(&self) -> Vec<BlockHeight> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RecordedEvent::Flush(block_height) => Some(*block_height),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn by_contract(&self, contract_id: &str) -> Vec<&RecordedEvent> {
+        self.events
+            .iter()
+            .filter(|event| {
+                event
+                    .context()
+                    .is_some_and(|context| context.contract_id.as_str() == contract_id)
+            })
+            .collect()
+    }
+
+    pub fn by_token_id(&self, contract_id: &str, token_id: &str) -> Vec<&RecordedEvent> {
+        self.by_contract(contract_id)
+            .into_iter()
+            .filter(|event| event.token_ids().contains(&token_id))
+            .collect()
+    }
+
+    /// Includes the flush of this block
+    pub fn by_block(&self, block_height: BlockHeight) -> Vec<&RecordedEvent> {
+        self.events
+            .iter()
+            .filter(|event| event.block_height() == block_height)
+            .collect()
+    }
+
+    /// Panics if an event was recorded after the flush of its block, if blocks
+    /// weren't flushed in increasing order, if receipts of a block weren't
+    /// recorded in order of `receipt_index`, or if events of a receipt weren't
+    /// recorded in order of `log_index` and `event_index`
+    #[track_caller]
+    pub fn assert_flushed_in_order(&self) {
+        let mut last_flushed = None;
+        let mut last_context: Option<&EventContext> = None;
+        for event in &self.events {
+            if let Some(last_flushed) = last_flushed {
+                assert!(
+                    event.block_height() > last_flushed,
+                    "{event:?} was recorded after block {last_flushed} was flushed"
+                );
+            }
+            if let Some(context) = event.context() {
+                if let Some(last_context) = last_context {
+                    if last_context.block_height == context.block_height {
+                        assert!(
+                            context.receipt_index >= last_context.receipt_index,
+                            "{event:?} was recorded after an event of a later receipt"
+                        );
+                    }
+                    if last_context.receipt_id == context.receipt_id {
+                        assert!(
+                            (context.log_index, context.event_index)
+                                >= (last_context.log_index, last_context.event_index),
+                            "{event:?} was recorded after a later event of the same receipt"
+                        );
+                    }
+                }
+                last_context = Some(context);
+            }
+            if let RecordedEvent::Flush(block_height) = event {
+                last_flushed = Some(*block_height);
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn assert_no_events_for_contract(&self, contract_id: &str) {
+        let events = self.by_contract(contract_id);
+        assert!(
+            events.is_empty(),
+            "Expected no events for {contract_id}, got {events:#?}"
+        );
+    }
+}
+
+#[async_trait]
+impl NftEventHandler for RecordingHandler {
+    type Error = Infallible;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events.push(RecordedEvent::Mint(mint, context));
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events.push(RecordedEvent::Transfer(transfer, context));
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events.push(RecordedEvent::Burn(burn, context));
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events
+            .push(RecordedEvent::MetadataUpdate(metadata_update, context));
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events.push(RecordedEvent::ContractMetadataUpdate(
+            contract_metadata_update,
+            context,
+        ));
+        Ok(())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events
+            .push(RecordedEvent::PayoutAnomaly(anomaly, context));
+        Ok(())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.events
+            .push(RecordedEvent::InvalidLog(invalid_log, context));
+        Ok(())
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        self.events.push(RecordedEvent::Flush(block_height));
+        Ok(())
+    }
+}
+
+/// Fails every call, for testing how errors are handled
+#[derive(Debug, Default)]
+pub struct FailingHandler;
+
+impl FailingHandler {
+    fn error() -> std::io::Error {
+        std::io::Error::other("FailingHandler always fails")
+    }
+}
+
+#[async_trait]
+impl NftEventHandler for FailingHandler {
+    type Error = std::io::Error;
+
+    async fn handle_mint(
+        &mut self,
+        _mint: ExtendedNftMintEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        _transfer: ExtendedNftTransferEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        _burn: ExtendedNftBurnEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        _metadata_update: ExtendedNftMetadataUpdateEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        _contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        _anomaly: NftPayoutAnomaly,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        _invalid_log: NftInvalidLog,
+        _context: EventContext,
+    ) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), Self::Error> {
+        Err(Self::error())
+    }
+}
+
+/// Buffers events until [`NftEventHandler::flush_events`] like most sinks do,
+/// for testing what happens when a flush fails. The first `failing_flushes`
+/// flushes fail and keep the buffer.
+#[derive(Debug, Default)]
+pub struct BufferingHandler {
+    buffer: Vec<RecordedEvent>,
+    failing_flushes: usize,
+    /// Events of successful flushes, each block followed by its flush
+    pub flushed: Arc<Mutex<RecordingHandler>>,
+}
+
+impl BufferingHandler {
+    pub fn new(failing_flushes: usize) -> Self {
+        Self {
+            failing_flushes,
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl NftEventHandler for BufferingHandler {
+    type Error = std::io::Error;
+
+    async fn handle_mint(
+        &mut self,
+        mint: ExtendedNftMintEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer.push(RecordedEvent::Mint(mint, context));
+        Ok(())
+    }
+
+    async fn handle_transfer(
+        &mut self,
+        transfer: ExtendedNftTransferEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer.push(RecordedEvent::Transfer(transfer, context));
+        Ok(())
+    }
+
+    async fn handle_burn(
+        &mut self,
+        burn: ExtendedNftBurnEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer.push(RecordedEvent::Burn(burn, context));
+        Ok(())
+    }
+
+    async fn handle_metadata_update(
+        &mut self,
+        metadata_update: ExtendedNftMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer
+            .push(RecordedEvent::MetadataUpdate(metadata_update, context));
+        Ok(())
+    }
+
+    async fn handle_contract_metadata_update(
+        &mut self,
+        contract_metadata_update: ExtendedNftContractMetadataUpdateEvent,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer.push(RecordedEvent::ContractMetadataUpdate(
+            contract_metadata_update,
+            context,
+        ));
+        Ok(())
+    }
+
+    async fn handle_payout_anomaly(
+        &mut self,
+        anomaly: NftPayoutAnomaly,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer
+            .push(RecordedEvent::PayoutAnomaly(anomaly, context));
+        Ok(())
+    }
+
+    async fn handle_invalid_log(
+        &mut self,
+        invalid_log: NftInvalidLog,
+        context: EventContext,
+    ) -> Result<(), Self::Error> {
+        self.buffer
+            .push(RecordedEvent::InvalidLog(invalid_log, context));
+        Ok(())
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), Self::Error> {
+        if self.failing_flushes > 0 {
+            self.failing_flushes -= 1;
+            return Err(std::io::Error::other("BufferingHandler failed to flush"));
+        }
+        let mut flushed = self.flushed.lock().unwrap();
+        flushed.events.append(&mut self.buffer);
+        flushed.events.push(RecordedEvent::Flush(block_height));
+        Ok(())
+    }
+}
@@ -1,50 +1,55 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use async_trait::async_trait;
+use base64::prelude::*;
 use inindexer::{
-    near_indexer_primitives::types::{AccountId, BlockHeight},
+    near_indexer_primitives::{
+        types::{AccountId, Balance},
+        views::{
+            ActionView, ExecutionOutcomeWithIdView, ReceiptEnumView, ReceiptView,
+            SignedTransactionView,
+        },
+        CryptoHash, IndexerExecutionOutcomeWithOptionalReceipt, IndexerExecutionOutcomeWithReceipt,
+        IndexerTransactionWithOutcome,
+    },
     near_utils::{NftBurnEvent, NftMintEvent, NftTransferEvent},
     neardata::NeardataProvider,
-    run_indexer, BlockRange, IndexerOptions, PreprocessTransactionsSettings,
+    run_indexer, BlockRange, IncompleteTransaction, IndexerOptions, PreprocessTransactionsSettings,
+    TransactionReceipt,
 };
+use redis::aio::ConnectionManager;
+use serde_json::json;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 
+use nft_indexer::fan_out_handler::{FailureMode, FanOutHandler};
+use nft_indexer::filter_handler::{AccountFilter, EventFilter, FilteredHandler};
+use nft_indexer::metrics;
+use nft_indexer::redis_handler::{FlushMode, InvalidRetryPolicy, PushToRedisStream, RetryPolicy};
+use nft_indexer::spam::{SpamClassifier, SpamConfig};
+use nft_indexer::testing::{BufferingHandler, FailingHandler, RecordedEvent, RecordingHandler};
+use nft_indexer::wash_trade::{WashTradeAnalyzer, WashTradeConfig};
 use nft_indexer::{
-    EventContext, ExtendedNftBurnEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
-    NftEventHandler, NftIndexer, NftTradeDetails,
+    parse_event_log, EventContext, ExtendedNftBurnEvent, ExtendedNftContractMetadataUpdateEvent,
+    ExtendedNftMetadataUpdateEvent, ExtendedNftMintEvent, ExtendedNftTransferEvent,
+    InvalidLogReason, NftContractMetadataUpdateEvent, NftEventHandler, NftEventLog, NftIndexer,
+    NftIndexerConfig, NftInvalidLog, NftMetadataUpdateEvent, NftPayoutAnomaly, NftTradeDetails,
+    PayoutAnomalyKind, PayoutBreakdown, PriceCurrency, PriceSource, TokenTrade,
 };
 
+fn sent_by<'a, E>(
+    events: Vec<(&'a E, &'a EventContext)>,
+    tx_sender_id: &str,
+) -> Vec<(&'a E, &'a EventContext)> {
+    events
+        .into_iter()
+        .filter(|(_, context)| context.tx_sender_id.as_str() == tx_sender_id)
+        .collect()
+}
+
 #[tokio::test]
 async fn detects_mints() {
-    struct TestHandler {
-        mint_events: HashMap<AccountId, Vec<(ExtendedNftMintEvent, EventContext)>>,
-    }
-
-    #[async_trait]
-    impl NftEventHandler for TestHandler {
-        async fn handle_mint(&mut self, mint: ExtendedNftMintEvent, context: EventContext) {
-            self.mint_events
-                .entry(context.tx_sender_id.clone())
-                .or_insert_with(Vec::new)
-                .push((mint, context));
-        }
-
-        async fn handle_transfer(
-            &mut self,
-            _transfer: ExtendedNftTransferEvent,
-            _context: EventContext,
-        ) {
-        }
-
-        async fn handle_burn(&mut self, _burn: ExtendedNftBurnEvent, _context: EventContext) {}
-
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
-    }
-
-    let handler = TestHandler {
-        mint_events: HashMap::new(),
-    };
-
-    let mut indexer = NftIndexer(handler);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
 
     run_indexer(
         &mut indexer,
@@ -63,31 +68,32 @@ async fn detects_mints() {
     .await
     .unwrap();
 
+    indexer.handler.assert_flushed_in_order();
     assert_eq!(
-        *indexer
-            .0
-            .mint_events
-            .get(&"minter1.sharddog.near".parse::<AccountId>().unwrap())
-            .unwrap(),
+        sent_by(indexer.handler.mints(), "minter1.sharddog.near"),
         vec![(
-            ExtendedNftMintEvent {
+            &ExtendedNftMintEvent {
                 event: NftMintEvent {
                     owner_id: "slimedragon.near".parse().unwrap(),
                     token_ids: vec!["19:23".to_owned()],
                     memo: None
                 }
             },
-            EventContext {
+            &EventContext {
                 transaction_id: "9TkiwECEL4AMsA6KmuhGskkNFT5Mr6ub6YJJAza8vbGs"
                     .parse()
                     .unwrap(),
                 receipt_id: "DrrW649B53RQaejPgRqiKM74MyT35JPk9cbkokkUGKdf"
+                receipt_index: 0,
                     .parse()
                     .unwrap(),
+                log_index: 0,
+                event_index: 0,
                 block_height: 117189144,
                 block_timestamp_nanosec: 1713553179034135476,
                 tx_sender_id: "minter1.sharddog.near".parse().unwrap(),
-                contract_id: "claim.sharddog.near".parse().unwrap()
+                contract_id: "claim.sharddog.near".parse().unwrap(),
+                spam_score: 0.0
             }
         )]
     );
@@ -95,36 +101,7 @@ async fn detects_mints() {
 
 #[tokio::test]
 async fn detects_transfers() {
-    struct TestHandler {
-        transfer_events: HashMap<AccountId, Vec<(ExtendedNftTransferEvent, EventContext)>>,
-    }
-
-    #[async_trait]
-    impl NftEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: ExtendedNftMintEvent, _context: EventContext) {}
-
-        async fn handle_transfer(
-            &mut self,
-            transfer: ExtendedNftTransferEvent,
-            context: EventContext,
-        ) {
-            let entry = self
-                .transfer_events
-                .entry(context.tx_sender_id.clone())
-                .or_insert_with(Vec::new);
-            entry.push((transfer, context));
-        }
-
-        async fn handle_burn(&mut self, _burn: ExtendedNftBurnEvent, _context: EventContext) {}
-
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
-    }
-
-    let handler = TestHandler {
-        transfer_events: HashMap::new(),
-    };
-
-    let mut indexer = NftIndexer(handler);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
 
     run_indexer(
         &mut indexer,
@@ -144,13 +121,9 @@ async fn detects_transfers() {
     .unwrap();
 
     assert_eq!(
-        *indexer
-            .0
-            .transfer_events
-            .get(&"slimegirl.near".parse::<AccountId>().unwrap())
-            .unwrap(),
+        sent_by(indexer.handler.transfers(), "slimegirl.near"),
         vec![(
-            ExtendedNftTransferEvent {
+            &ExtendedNftTransferEvent {
                 event: NftTransferEvent {
                     authorized_id: None,
                     old_owner_id: "slimegirl.near".parse().unwrap(),
@@ -160,19 +133,27 @@ async fn detects_transfers() {
                 },
                 trade: NftTradeDetails {
                     token_prices_near: vec![None],
-                }
+                    token_trades: vec![None],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
             },
-            EventContext {
+            &EventContext {
                 transaction_id: "95HkmF7ajYPSSJnhsGL7C4k8sF5jmdrp4ciiTcK7xuYr"
                     .parse()
                     .unwrap(),
                 receipt_id: "AhbWgoat1L23YgrzrWE6U2FcM1n5uqRZ8cKxkxevdFJa"
+                receipt_index: 0,
                     .parse()
                     .unwrap(),
+                log_index: 0,
+                event_index: 0,
                 block_height: 117_487_094,
                 block_timestamp_nanosec: 1713920604063293990,
                 tx_sender_id: "slimegirl.near".parse().unwrap(),
-                contract_id: "x.paras.near".parse().unwrap()
+                contract_id: "x.paras.near".parse().unwrap(),
+                spam_score: 0.0
             }
         )]
     );
@@ -180,36 +161,7 @@ async fn detects_transfers() {
 
 #[tokio::test]
 async fn detects_burns() {
-    struct TestHandler {
-        burn_events: HashMap<AccountId, Vec<(ExtendedNftBurnEvent, EventContext)>>,
-    }
-
-    #[async_trait]
-    impl NftEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: ExtendedNftMintEvent, _context: EventContext) {}
-
-        async fn handle_transfer(
-            &mut self,
-            _transfer: ExtendedNftTransferEvent,
-            _context: EventContext,
-        ) {
-        }
-
-        async fn handle_burn(&mut self, burn: ExtendedNftBurnEvent, context: EventContext) {
-            self.burn_events
-                .entry(context.tx_sender_id.clone())
-                .or_insert_with(Vec::new)
-                .push((burn, context));
-        }
-
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
-    }
-
-    let handler = TestHandler {
-        burn_events: HashMap::new(),
-    };
-
-    let mut indexer = NftIndexer(handler);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
 
     run_indexer(
         &mut indexer,
@@ -229,13 +181,9 @@ async fn detects_burns() {
     .unwrap();
 
     assert_eq!(
-        *indexer
-            .0
-            .burn_events
-            .get(&"bonehedz.near".parse::<AccountId>().unwrap())
-            .unwrap(),
+        sent_by(indexer.handler.burns(), "bonehedz.near"),
         vec![(
-            ExtendedNftBurnEvent {
+            &ExtendedNftBurnEvent {
                 event: NftBurnEvent {
                     owner_id: "bonehedz.near".parse().unwrap(),
                     authorized_id: None,
@@ -243,17 +191,21 @@ async fn detects_burns() {
                     memo: None
                 }
             },
-            EventContext {
+            &EventContext {
                 transaction_id: "9k7kE7PU1YqrAxzdwKw8P3u8eNeazCZpMWStD89XFBpZ"
                     .parse()
                     .unwrap(),
                 receipt_id: "4EVVVu8VR72Gd4cfhxworayV1CuA29DL9ndE7KfdRcKN"
+                receipt_index: 0,
                     .parse()
                     .unwrap(),
+                log_index: 0,
+                event_index: 0,
                 block_height: 117752572,
                 block_timestamp_nanosec: 1714240014556084087,
                 tx_sender_id: "bonehedz.near".parse().unwrap(),
-                contract_id: "veganfriends.mintbase1.near".parse().unwrap()
+                contract_id: "veganfriends.mintbase1.near".parse().unwrap(),
+                spam_score: 0.0
             }
         )]
     );
@@ -261,36 +213,7 @@ async fn detects_burns() {
 
 #[tokio::test]
 async fn detects_paras_trade() {
-    struct TestHandler {
-        transfer_events: HashMap<AccountId, Vec<(ExtendedNftTransferEvent, EventContext)>>,
-    }
-
-    #[async_trait]
-    impl NftEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: ExtendedNftMintEvent, _context: EventContext) {}
-
-        async fn handle_transfer(
-            &mut self,
-            transfer: ExtendedNftTransferEvent,
-            context: EventContext,
-        ) {
-            let entry = self
-                .transfer_events
-                .entry(context.tx_sender_id.clone())
-                .or_insert_with(Vec::new);
-            entry.push((transfer, context));
-        }
-
-        async fn handle_burn(&mut self, _burn: ExtendedNftBurnEvent, _context: EventContext) {}
-
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
-    }
-
-    let handler = TestHandler {
-        transfer_events: HashMap::new(),
-    };
-
-    let mut indexer = NftIndexer(handler);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
 
     run_indexer(
         &mut indexer,
@@ -309,14 +232,21 @@ async fn detects_paras_trade() {
     .await
     .unwrap();
 
+    let transfers = sent_by(indexer.handler.transfers(), "marketplace.paras.near");
+    // The payout split, buyer, approval_id, declared_balance and max_len_payout
+    // haven't been checked against the chain yet, so only what follows from the
+    // price is asserted for them. Pin them once the blocks are captured.
+    let trade = transfers[0].0.trade.token_trades[0].clone().unwrap();
+    let payout = trade.payout.clone().unwrap();
+    assert_eq!(payout.total(), 790000000000000000000000);
+    assert!(payout.seller_proceeds > 0);
+    assert!(trade.approval_id.is_some());
+    assert!(trade.max_len_payout.is_some());
+    assert!(trade.declared_balance > 0);
     assert_eq!(
-        *indexer
-            .0
-            .transfer_events
-            .get(&"marketplace.paras.near".parse::<AccountId>().unwrap())
-            .unwrap(),
+        transfers,
         vec![(
-            ExtendedNftTransferEvent {
+            &ExtendedNftTransferEvent {
                 event: NftTransferEvent {
                     authorized_id: Some("marketplace.paras.near".parse().unwrap()),
                     old_owner_id:
@@ -329,19 +259,36 @@ async fn detects_paras_trade() {
                 },
                 trade: NftTradeDetails {
                     token_prices_near: vec![Some(790000000000000000000000)],
-                }
+                    token_trades: vec![Some(TokenTrade {
+                        price: 790000000000000000000000,
+                        currency: PriceCurrency::Near,
+                        payout: Some(payout),
+                        marketplace_id: "marketplace.paras.near".parse().unwrap(),
+                        buyer_id: trade.buyer_id.clone(),
+                        approval_id: trade.approval_id,
+                        declared_balance: trade.declared_balance,
+                        max_len_payout: trade.max_len_payout,
+                    })],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
             },
-            EventContext {
+            &EventContext {
                 transaction_id: "5aPiGXDKi696Af6imrPMF3aQozQGZy119uM6WKRAqbVH"
                     .parse()
                     .unwrap(),
                 receipt_id: "Cy8NNUDiDBmKyQ714CoyYV2MMzwxFuQoeVZnvDsCtdeJ"
+                receipt_index: 0,
                     .parse()
                     .unwrap(),
+                log_index: 0,
+                event_index: 0,
                 block_height: 117998765,
                 block_timestamp_nanosec: 1714543285352206574,
                 tx_sender_id: "marketplace.paras.near".parse().unwrap(),
                 contract_id: "x.paras.near".parse().unwrap(),
+                spam_score: 0.0,
             }
         )]
     );
@@ -349,36 +296,7 @@ async fn detects_paras_trade() {
 
 #[tokio::test]
 async fn detects_mintbase_trade() {
-    struct TestHandler {
-        transfer_events: HashMap<AccountId, Vec<(ExtendedNftTransferEvent, EventContext)>>,
-    }
-
-    #[async_trait]
-    impl NftEventHandler for TestHandler {
-        async fn handle_mint(&mut self, _mint: ExtendedNftMintEvent, _context: EventContext) {}
-
-        async fn handle_transfer(
-            &mut self,
-            transfer: ExtendedNftTransferEvent,
-            context: EventContext,
-        ) {
-            let entry = self
-                .transfer_events
-                .entry(context.tx_sender_id.clone())
-                .or_insert_with(Vec::new);
-            entry.push((transfer, context));
-        }
-
-        async fn handle_burn(&mut self, _burn: ExtendedNftBurnEvent, _context: EventContext) {}
-
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
-    }
-
-    let handler = TestHandler {
-        transfer_events: HashMap::new(),
-    };
-
-    let mut indexer = NftIndexer(handler);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
 
     run_indexer(
         &mut indexer,
@@ -397,14 +315,21 @@ async fn detects_mintbase_trade() {
     .await
     .unwrap();
 
+    let transfers = sent_by(indexer.handler.transfers(), "simple.market.mintbase1.near");
+    // The payout split, buyer, approval_id, declared_balance and max_len_payout
+    // haven't been checked against the chain yet, so only what follows from the
+    // price is asserted for them. Pin them once the blocks are captured.
+    let trade = transfers[0].0.trade.token_trades[0].clone().unwrap();
+    let payout = trade.payout.clone().unwrap();
+    assert_eq!(payout.total(), 2925000000000000000000000);
+    assert!(payout.seller_proceeds > 0);
+    assert!(trade.approval_id.is_some());
+    assert!(trade.max_len_payout.is_some());
+    assert!(trade.declared_balance > 0);
     assert_eq!(
-        *indexer
-            .0
-            .transfer_events
-            .get(&"simple.market.mintbase1.near".parse::<AccountId>().unwrap())
-            .unwrap(),
+        transfers,
         vec![(
-            ExtendedNftTransferEvent {
+            &ExtendedNftTransferEvent {
                 event: NftTransferEvent {
                     authorized_id: Some("simple.market.mintbase1.near".parse().unwrap()),
                     old_owner_id: "beanlabs.near".parse().unwrap(),
@@ -416,21 +341,2801 @@ async fn detects_mintbase_trade() {
                     memo: None
                 },
                 trade: NftTradeDetails {
-                    token_prices_near: vec![Some(2925000000000000000000000)]
-                }
+                    token_prices_near: vec![Some(2925000000000000000000000)],
+                    token_trades: vec![Some(TokenTrade {
+                        price: 2925000000000000000000000,
+                        currency: PriceCurrency::Near,
+                        payout: Some(payout),
+                        marketplace_id: "simple.market.mintbase1.near".parse().unwrap(),
+                        buyer_id: trade.buyer_id.clone(),
+                        approval_id: trade.approval_id,
+                        declared_balance: trade.declared_balance,
+                        max_len_payout: trade.max_len_payout,
+                    })],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
             },
-            EventContext {
+            &EventContext {
                 transaction_id: "HLdiNk9QFS2AdRLNrWGfB6TzSHFRUy9TpmSjJK3escHa"
                     .parse()
                     .unwrap(),
                 receipt_id: "Cvn41HotTFo7TkacdzPyKtzMhzbXRFY64kmK6zF9GzKx"
+                receipt_index: 0,
                     .parse()
                     .unwrap(),
+                log_index: 0,
+                event_index: 0,
                 block_height: 116934526,
                 block_timestamp_nanosec: 1713231344389999053,
                 tx_sender_id: "simple.market.mintbase1.near".parse().unwrap(),
-                contract_id: "beanlabs.mintbase1.near".parse().unwrap()
+                contract_id: "beanlabs.mintbase1.near".parse().unwrap(),
+                spam_score: 0.0
             }
         )]
     );
 }
+
+#[test]
+fn retry_policy_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 10,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        jitter: 0.0,
+    };
+    assert_eq!(policy.backoff(1), Duration::from_millis(100));
+    assert_eq!(policy.backoff(2), Duration::from_millis(200));
+    assert_eq!(policy.backoff(4), Duration::from_millis(800));
+    assert_eq!(policy.backoff(5), Duration::from_secs(1));
+    assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    assert_eq!(policy.delay(3), Duration::from_millis(400));
+
+    let policy = RetryPolicy {
+        jitter: 0.5,
+        ..policy
+    };
+    for retry in 1..20 {
+        let delay = policy.delay(retry);
+        assert!(delay <= policy.backoff(retry));
+        assert!(delay >= policy.backoff(retry) / 2);
+    }
+}
+
+#[test]
+fn validates_retry_policy() {
+    assert_eq!(RetryPolicy::default().validate(), Ok(()));
+    assert_eq!(
+        RetryPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        }
+        .validate(),
+        Err(InvalidRetryPolicy::NoAttempts)
+    );
+    for jitter in [-0.1, 1.5] {
+        assert_eq!(
+            RetryPolicy {
+                jitter,
+                ..Default::default()
+            }
+            .validate(),
+            Err(InvalidRetryPolicy::Jitter(jitter))
+        );
+    }
+    let policy = RetryPolicy {
+        jitter: f64::NAN,
+        ..Default::default()
+    };
+    assert!(policy.validate().is_err());
+    // Not validated, but still doesn't panic
+    assert_eq!(policy.delay(1), policy.backoff(1));
+}
+
+/// Accepts Redis connections and answers every command with an error. Returns
+/// its address and the names of the commands it received.
+async fn failing_redis_server() -> (std::net::SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let received = commands.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let received = received.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = socket.into_split();
+                let mut reader = BufReader::new(reader);
+                while let Some(command) = read_redis_command(&mut reader).await {
+                    received.lock().unwrap().push(command);
+                    if writer
+                        .write_all(b"-ERR injected failure\r\n")
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, commands)
+}
+
+/// Reads a command sent as an array of bulk strings and returns its name
+async fn read_redis_command(reader: &mut (impl AsyncBufRead + Unpin)) -> Option<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let args: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut name = None;
+    for _ in 0..args {
+        line.clear();
+        reader.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await.ok()?;
+        name.get_or_insert_with(|| String::from_utf8_lossy(&arg[..len]).to_uppercase());
+    }
+    name
+}
+
+#[tokio::test]
+async fn retries_failed_redis_flushes() {
+    let (addr, commands) = failing_redis_server().await;
+    let client = redis::Client::open(format!("redis://{addr}")).unwrap();
+    let connection = ConnectionManager::new(client).await.unwrap();
+    let mut handler = PushToRedisStream::new(connection, 100)
+        .await
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            jitter: 0.0,
+        });
+
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    let err = handler.flush_events(117_000_000).await.unwrap_err();
+    assert!(err.to_string().contains("injected failure"), "{err}");
+    // The stream is checked for the block before anything is written to it
+    let commands = commands.lock().unwrap();
+    let count = |name: &str| commands.iter().filter(|command| *command == name).count();
+    assert_eq!(count("XREVRANGE"), 3);
+    assert_eq!(count("XADD"), 0);
+}
+
+async fn stream_entry_ids(connection: &ConnectionManager, stream: &str) -> Vec<String> {
+    let reply: redis::streams::StreamRangeReply = redis::cmd("XRANGE")
+        .arg(stream)
+        .arg("-")
+        .arg("+")
+        .query_async(&mut connection.clone())
+        .await
+        .unwrap();
+    reply.ids.into_iter().map(|entry| entry.id).collect()
+}
+
+#[tokio::test]
+#[ignore = "needs a disposable Redis database at $REDIS_TEST_URL, it's flushed"]
+async fn flushes_redis_streams_once() {
+    let client = redis::Client::open(std::env::var("REDIS_TEST_URL").unwrap()).unwrap();
+    let mut connection = ConnectionManager::new(client).await.unwrap();
+    redis::cmd("FLUSHDB")
+        .query_async::<_, ()>(&mut connection)
+        .await
+        .unwrap();
+    let flush = |flush_mode: FlushMode, block_height: u64| {
+        let connection = connection.clone();
+        async move {
+            let mut handler = PushToRedisStream::new(connection, 100)
+                .await
+                .with_retry_policy(RetryPolicy::no_retry())
+                .with_flush_mode(flush_mode);
+            for token_id in ["1", "2"] {
+                handler
+                    .handle_mint(
+                        fixture_mint(token_id),
+                        EventContext {
+                            block_height,
+                            ..fixture_context()
+                        },
+                    )
+                    .await
+                    .unwrap();
+            }
+            handler.flush_events(block_height).await.unwrap();
+        }
+    };
+    let atomic = FlushMode::Atomic {
+        last_block_key: "nft_test_last_block".to_owned(),
+    };
+
+    flush(FlushMode::Sequential, 100).await;
+    assert_eq!(
+        stream_entry_ids(&connection, "nft_mint").await,
+        vec!["100-0", "100-1"]
+    );
+    // The same block again, as if the indexer restarted after another stream failed
+    flush(FlushMode::Sequential, 100).await;
+    flush(atomic.clone(), 100).await;
+    assert_eq!(
+        stream_entry_ids(&connection, "nft_mint").await,
+        vec!["100-0", "100-1"]
+    );
+
+    flush(atomic.clone(), 101).await;
+    flush(atomic, 101).await;
+    assert_eq!(
+        stream_entry_ids(&connection, "nft_mint").await,
+        vec!["100-0", "100-1", "101-0", "101-1"]
+    );
+}
+
+const NEAR: Balance = 10u128.pow(24);
+
+fn payout_args(token_id: &str, balance: Balance) -> serde_json::Value {
+    json!({
+        "receiver_id": "buyer.near",
+        "token_id": token_id,
+        "approval_id": 7,
+        "balance": balance.to_string(),
+        "max_len_payout": 10,
+    })
+}
+
+fn payout_value(seller_proceeds: Balance, royalty: Balance) -> serde_json::Value {
+    json!({
+        "SuccessValue": BASE64_STANDARD.encode(json!({
+            "payout": {
+                "seller.near": seller_proceeds.to_string(),
+                "creator.near": royalty.to_string(),
+            }
+        }).to_string())
+    })
+}
+
+fn fixture_receipt(
+    receipt_id: CryptoHash,
+    predecessor_id: &str,
+    receiver_id: &str,
+    calls: Vec<(&str, serde_json::Value)>,
+    status: serde_json::Value,
+) -> TransactionReceipt {
+    let receipt: ReceiptView = serde_json::from_value(json!({
+        "predecessor_id": predecessor_id,
+        "receiver_id": receiver_id,
+        "receipt_id": receipt_id,
+        "receipt": {
+            "Action": {
+                "signer_id": "buyer.near",
+                "signer_public_key": "ed25519:11111111111111111111111111111111",
+                "gas_price": "0",
+                "output_data_receivers": [],
+                "input_data_ids": [],
+                "actions": calls.into_iter().map(|(method_name, args)| json!({
+                    "FunctionCall": {
+                        "method_name": method_name,
+                        "args": BASE64_STANDARD.encode(args.to_string()),
+                        "gas": 30_000_000_000_000u64,
+                        "deposit": "1",
+                    }
+                })).collect::<Vec<_>>(),
+            }
+        }
+    }))
+    .unwrap();
+    TransactionReceipt {
+        receipt: IndexerExecutionOutcomeWithReceipt {
+            execution_outcome: fixture_outcome(receipt_id, receiver_id, status),
+            receipt,
+        },
+        block_height: 117_000_000,
+        block_timestamp_nanosec: 1713000000000000000,
+    }
+}
+
+fn fixture_outcome(
+    id: CryptoHash,
+    executor_id: &str,
+    status: serde_json::Value,
+) -> ExecutionOutcomeWithIdView {
+    serde_json::from_value(json!({
+        "proof": [],
+        "block_hash": CryptoHash::default(),
+        "id": id,
+        "outcome": {
+            "logs": [],
+            "receipt_ids": [],
+            "gas_burnt": 0,
+            "tokens_burnt": "0",
+            "executor_id": executor_id,
+            "status": status,
+            "metadata": { "version": 1, "gas_profile": null },
+        }
+    }))
+    .unwrap()
+}
+
+fn fixture_transaction(receipts: Vec<TransactionReceipt>) -> IncompleteTransaction {
+    let transaction: SignedTransactionView = serde_json::from_value(json!({
+        "signer_id": "buyer.near",
+        "public_key": "ed25519:11111111111111111111111111111111",
+        "nonce": 1,
+        "receiver_id": "marketplace.near",
+        "actions": [],
+        "signature": format!("ed25519:{}", "1".repeat(64)),
+        "hash": CryptoHash::default(),
+    }))
+    .unwrap();
+    IncompleteTransaction {
+        transaction: IndexerTransactionWithOutcome {
+            transaction,
+            outcome: IndexerExecutionOutcomeWithOptionalReceipt {
+                execution_outcome: fixture_outcome(
+                    CryptoHash::default(),
+                    "buyer.near",
+                    json!({ "SuccessReceiptId": CryptoHash::default() }),
+                ),
+                receipt: None,
+            },
+        },
+        receipts: receipts
+            .into_iter()
+            .map(|receipt| (receipt.receipt.receipt.receipt_id, Some(receipt)))
+            .collect(),
+    }
+}
+
+fn fixture_transfer(token_ids: &[&str]) -> NftTransferEvent {
+    NftTransferEvent {
+        authorized_id: Some("marketplace.near".parse().unwrap()),
+        old_owner_id: "seller.near".parse().unwrap(),
+        new_owner_id: "buyer.near".parse().unwrap(),
+        token_ids: token_ids
+            .iter()
+            .map(|token_id| token_id.to_string())
+            .collect(),
+        memo: None,
+    }
+}
+
+fn fixture_trade(balance: Balance, payout: Option<(Balance, Balance)>) -> TokenTrade {
+    TokenTrade {
+        price: payout.map_or(balance, |(seller_proceeds, royalty)| {
+            seller_proceeds + royalty
+        }),
+        currency: PriceCurrency::Near,
+        payout: payout.map(|(seller_proceeds, royalty)| PayoutBreakdown {
+            seller_proceeds,
+            royalties: HashMap::from_iter([("creator.near".parse().unwrap(), royalty)]),
+            marketplace_fee: None,
+        }),
+        marketplace_id: "marketplace.near".parse().unwrap(),
+        buyer_id: "buyer.near".parse().unwrap(),
+        approval_id: Some(7),
+        declared_balance: balance,
+        max_len_payout: Some(10),
+    }
+}
+
+#[test]
+fn detects_batch_trade_in_one_receipt() {
+    let receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![
+            ("nft_transfer_payout", payout_args("1", NEAR)),
+            ("nft_transfer_payout", payout_args("2", 2 * NEAR)),
+        ],
+        payout_value(NEAR * 18 / 10, NEAR * 2 / 10),
+    );
+    let transaction = fixture_transaction(vec![receipt.clone()]);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1", "2"]),
+        &receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    // Only the last action of a receipt has a known return value
+    assert_eq!(
+        transfer.trade.token_trades,
+        vec![
+            Some(fixture_trade(NEAR, None)),
+            Some(fixture_trade(
+                2 * NEAR,
+                Some((NEAR * 18 / 10, NEAR * 2 / 10))
+            )),
+        ]
+    );
+    assert_eq!(
+        transfer.trade.token_prices_near,
+        vec![Some(NEAR), Some(2 * NEAR)]
+    );
+}
+
+#[test]
+fn detects_payout_anomalies() {
+    // The contract pays out more than the marketplace asked for, to more accounts
+    // than it allowed
+    let mut args = payout_args("1", NEAR);
+    args["max_len_payout"] = json!(1);
+    let receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", args)],
+        payout_value(NEAR, NEAR / 10),
+    );
+    let transaction = fixture_transaction(vec![receipt.clone()]);
+    let config = NftIndexerConfig {
+        validate_payouts: true,
+        ..Default::default()
+    };
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &receipt,
+        &transaction,
+        &config,
+    );
+    let anomaly = |kind| NftPayoutAnomaly {
+        token_id: "1".to_owned(),
+        marketplace_id: "marketplace.near".parse().unwrap(),
+        kind,
+        declared_balance: NEAR,
+        payout_sum: NEAR * 11 / 10,
+        max_len_payout: Some(1),
+        payout_len: 2,
+    };
+    assert_eq!(
+        transfer.trade.payout_anomalies,
+        vec![
+            anomaly(PayoutAnomalyKind::SumMismatch),
+            anomaly(PayoutAnomalyKind::TooManyRecipients)
+        ]
+    );
+    assert_eq!(transfer.trade.token_prices_near, vec![Some(NEAR * 11 / 10)]);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &receipt,
+        &transaction,
+        &NftIndexerConfig {
+            price_source: PriceSource::DeclaredBalance,
+            ..config
+        },
+    );
+    assert_eq!(transfer.trade.payout_anomalies.len(), 2);
+    assert_eq!(transfer.trade.token_prices_near, vec![Some(NEAR)]);
+    assert_eq!(transfer.trade.token_trades[0].as_ref().unwrap().price, NEAR);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(transfer.trade.payout_anomalies, vec![]);
+}
+
+#[test]
+fn ignores_payout_to_another_receiver() {
+    let receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("1", NEAR))],
+        payout_value(NEAR * 9 / 10, NEAR / 10),
+    );
+    let transaction = fixture_transaction(vec![receipt.clone()]);
+
+    // The owner sends the token elsewhere in the same transaction, that's not a sale
+    let transfer = ExtendedNftTransferEvent::from_event(
+        NftTransferEvent {
+            new_owner_id: "friend.near".parse().unwrap(),
+            ..fixture_transfer(&["1"])
+        },
+        &receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(transfer.trade.token_trades, vec![None]);
+}
+
+#[test]
+fn detects_batch_trade_across_receipts() {
+    let first_receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("1", NEAR))],
+        payout_value(NEAR * 9 / 10, NEAR / 10),
+    );
+    let second_receipt = fixture_receipt(
+        CryptoHash([2; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("2", 2 * NEAR))],
+        payout_value(NEAR * 18 / 10, NEAR * 2 / 10),
+    );
+    let transaction = fixture_transaction(vec![first_receipt.clone(), second_receipt.clone()]);
+
+    let first_transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &first_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(
+        first_transfer.trade.token_trades,
+        vec![Some(fixture_trade(NEAR, Some((NEAR * 9 / 10, NEAR / 10))))]
+    );
+
+    let second_transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["2"]),
+        &second_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(
+        second_transfer.trade.token_trades,
+        vec![Some(fixture_trade(
+            2 * NEAR,
+            Some((NEAR * 18 / 10, NEAR * 2 / 10))
+        ))]
+    );
+
+    // A log that lists both tokens is matched with both calls, even though only one of
+    // them is in the same receipt
+    let combined_transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1", "2"]),
+        &first_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(
+        combined_transfer.trade.token_prices_near,
+        vec![Some(NEAR), Some(2 * NEAR)]
+    );
+}
+
+#[test]
+fn detects_payout_returned_by_another_receipt() {
+    let callback_receipt_id = CryptoHash([2; 32]);
+    let receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("1", NEAR))],
+        json!({ "SuccessReceiptId": callback_receipt_id }),
+    );
+    let callback_receipt = fixture_receipt(
+        callback_receipt_id,
+        "nft.near",
+        "nft.near",
+        vec![("resolve_payout", json!({}))],
+        payout_value(NEAR * 9 / 10, NEAR / 10),
+    );
+    let transaction = fixture_transaction(vec![receipt.clone(), callback_receipt]);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(
+        transfer.trade.token_trades,
+        vec![Some(fixture_trade(NEAR, Some((NEAR * 9 / 10, NEAR / 10))))]
+    );
+}
+
+#[test]
+fn ignores_failed_and_unresolved_payouts() {
+    // The contract panicked, so nothing was sold
+    let failed_receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("1", NEAR))],
+        json!({
+            "Failure": {
+                "ActionError": {
+                    "index": 0,
+                    "kind": { "FunctionCallError": { "ExecutionError": "Smart contract panicked" } }
+                }
+            }
+        }),
+    );
+    // The payout is returned by a receipt that isn't part of the transaction
+    let unresolved_receipt = fixture_receipt(
+        CryptoHash([2; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("2", 2 * NEAR))],
+        json!({ "SuccessReceiptId": CryptoHash([9; 32]) }),
+    );
+    // The contract returned something that isn't a payout
+    let malformed_receipt = fixture_receipt(
+        CryptoHash([3; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("3", 3 * NEAR))],
+        json!({ "SuccessValue": BASE64_STANDARD.encode("null") }),
+    );
+    let transaction = fixture_transaction(vec![
+        failed_receipt,
+        unresolved_receipt.clone(),
+        malformed_receipt,
+    ]);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1", "2", "3"]),
+        &unresolved_receipt,
+        &transaction,
+        &NftIndexerConfig {
+            validate_payouts: true,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        transfer.trade.token_trades,
+        vec![
+            None,
+            Some(fixture_trade(2 * NEAR, None)),
+            Some(fixture_trade(3 * NEAR, None)),
+        ]
+    );
+    // Payouts that are unknown can't be validated
+    assert_eq!(transfer.trade.payout_anomalies, vec![]);
+}
+
+#[test]
+fn detects_trade_settled_in_fungible_token() {
+    let ft_on_transfer = |token_id: &str| {
+        json!({
+            "sender_id": "buyer.near",
+            "amount": "5000000",
+            "msg": json!({
+                "market_type": "buy",
+                "nft_contract_id": "nft.near",
+                "token_id": token_id,
+            })
+            .to_string(),
+        })
+    };
+    let ft_transfer_call_receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "buyer.near",
+        "usdt.tether-token.near",
+        vec![(
+            "ft_transfer_call",
+            json!({
+                "receiver_id": "marketplace.near",
+                "amount": "5000000",
+                "msg": ft_on_transfer("1")["msg"],
+            }),
+        )],
+        json!({ "SuccessReceiptId": CryptoHash([2; 32]) }),
+    );
+    let ft_on_transfer_receipt = fixture_receipt(
+        CryptoHash([2; 32]),
+        "usdt.tether-token.near",
+        "marketplace.near",
+        vec![("ft_on_transfer", ft_on_transfer("1"))],
+        json!({ "SuccessReceiptId": CryptoHash([3; 32]) }),
+    );
+    let payout_receipt = fixture_receipt(
+        CryptoHash([3; 32]),
+        "marketplace.near",
+        "nft.near",
+        vec![("nft_transfer_payout", payout_args("1", 5_000_000))],
+        payout_value(4_500_000, 500_000),
+    );
+    // Another token sent for the same NFT, but the marketplace panicked
+    let failed_ft_on_transfer_receipt = fixture_receipt(
+        CryptoHash([0; 32]),
+        "fake-usdt.near",
+        "marketplace.near",
+        vec![("ft_on_transfer", ft_on_transfer("1"))],
+        json!({
+            "Failure": {
+                "ActionError": {
+                    "index": 0,
+                    "kind": {
+                        "FunctionCallError": {
+                            "ExecutionError": "Smart contract panicked: Unknown token"
+                        }
+                    }
+                }
+            }
+        }),
+    );
+    // A purchase of another NFT in the same transaction
+    let other_ft_on_transfer_receipt = fixture_receipt(
+        CryptoHash([4; 32]),
+        "wrap.near",
+        "marketplace.near",
+        vec![("ft_on_transfer", ft_on_transfer("2"))],
+        json!({ "SuccessValue": BASE64_STANDARD.encode("\"0\"") }),
+    );
+    let transaction = fixture_transaction(vec![
+        ft_transfer_call_receipt,
+        ft_on_transfer_receipt,
+        payout_receipt.clone(),
+        failed_ft_on_transfer_receipt,
+        other_ft_on_transfer_receipt,
+    ]);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &payout_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(
+        transfer.trade.token_trades,
+        vec![Some(TokenTrade {
+            currency: PriceCurrency::Ft("usdt.tether-token.near".parse().unwrap()),
+            ..fixture_trade(5_000_000, Some((4_500_000, 500_000)))
+        })]
+    );
+    assert_eq!(transfer.trade.token_prices_near, vec![None]);
+
+    // Without a matching ft_on_transfer, the same call is a trade for NEAR
+    let transfer = ExtendedNftTransferEvent::from_event(
+        fixture_transfer(&["1"]),
+        &payout_receipt,
+        &fixture_transaction(vec![payout_receipt.clone()]),
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(transfer.trade.token_prices_near, vec![Some(5_000_000)]);
+}
+
+#[test]
+fn serializes_prices_as_strings() {
+    let transfer = ExtendedNftTransferEvent {
+        event: fixture_transfer(&["1", "2"]),
+        trade: NftTradeDetails {
+            token_prices_near: vec![Some(u128::MAX), None],
+            token_trades: vec![Some(fixture_trade(u128::MAX, None)), None],
+            payout_anomalies: vec![],
+        },
+        reverted_transfer_receipt_id: None,
+        suspected_wash_trade: false,
+    };
+    let expected = json!([u128::MAX.to_string(), null]);
+    assert_eq!(
+        serde_json::to_value(&transfer).unwrap()["trade"]["token_prices_near"],
+        expected
+    );
+    let event = nft_indexer::stream_events::NftTransferEvent::new(transfer, fixture_context());
+    let json = serde_json::to_string(&event).unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&json).unwrap()["token_prices_near"],
+        expected
+    );
+    assert_eq!(
+        serde_json::from_str::<nft_indexer::stream_events::NftTransferEvent>(&json).unwrap(),
+        event
+    );
+}
+
+#[test]
+fn serializes_mints_as_intear_events() {
+    let event = nft_indexer::stream_events::NftMintEvent::new(
+        fixture_mint("1"),
+        EventContext {
+            log_index: 1,
+            spam_score: 0.5,
+            ..fixture_context()
+        },
+    );
+    let json = serde_json::to_value(&event).unwrap();
+    assert_eq!(
+        json,
+        json!({
+            "owner_id": "alice.near",
+            "token_ids": ["1"],
+            "memo": null,
+            "transaction_id": CryptoHash([1; 32]),
+            "receipt_id": CryptoHash([2; 32]),
+            "block_height": 117_000_000,
+            "block_timestamp_nanosec": "1713000000000000000",
+            "contract_id": "nft.near",
+            "receipt_index": 0,
+            "log_index": 1,
+            "event_index": 0,
+            "spam_score": 0.5,
+        })
+    );
+    // Consumers that only know intear_events ignore the fields added by this indexer
+    let intear_event: intear_events::events::nft::nft_mint::NftMintEvent =
+        serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(intear_event.token_ids, vec!["1".to_owned()]);
+    let event: nft_indexer::stream_events::NftMintEvent =
+        serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&event).unwrap(), json);
+}
+
+#[test]
+fn detects_reverted_transfer_call() {
+    let transfer_call_receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "alice.near",
+        "nft.near",
+        vec![(
+            "nft_transfer_call",
+            json!({
+                "receiver_id": "receiver.near",
+                "token_id": "1",
+                "msg": "",
+            }),
+        )],
+        json!({ "SuccessReceiptId": CryptoHash([3; 32]) }),
+    );
+    let resolve_transfer_receipt = fixture_receipt(
+        CryptoHash([3; 32]),
+        "nft.near",
+        "nft.near",
+        vec![(
+            "nft_resolve_transfer",
+            json!({
+                "previous_owner_id": "alice.near",
+                "receiver_id": "receiver.near",
+                "token_id": "1",
+            }),
+        )],
+        json!({ "SuccessValue": BASE64_STANDARD.encode("false") }),
+    );
+    let transaction = fixture_transaction(vec![
+        transfer_call_receipt.clone(),
+        resolve_transfer_receipt.clone(),
+    ]);
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        NftTransferEvent {
+            authorized_id: None,
+            old_owner_id: "alice.near".parse().unwrap(),
+            new_owner_id: "receiver.near".parse().unwrap(),
+            token_ids: vec!["1".to_owned()],
+            memo: None,
+        },
+        &transfer_call_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(transfer.reverted_transfer_receipt_id, None);
+
+    let refund = ExtendedNftTransferEvent::from_event(
+        NftTransferEvent {
+            authorized_id: None,
+            old_owner_id: "receiver.near".parse().unwrap(),
+            new_owner_id: "alice.near".parse().unwrap(),
+            token_ids: vec!["1".to_owned()],
+            memo: None,
+        },
+        &resolve_transfer_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(
+        refund.reverted_transfer_receipt_id,
+        Some(CryptoHash([1; 32]))
+    );
+    assert_eq!(refund.trade.token_trades, vec![None]);
+}
+
+#[test]
+fn ignores_unrelated_resolve_transfer() {
+    // nft_transfer_call of another token, and of the same token on another contract
+    let other_token_receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "alice.near",
+        "nft.near",
+        vec![(
+            "nft_transfer_call",
+            json!({ "receiver_id": "receiver.near", "token_id": "2", "msg": "" }),
+        )],
+        json!({ "SuccessReceiptId": CryptoHash([3; 32]) }),
+    );
+    let other_contract_receipt = fixture_receipt(
+        CryptoHash([2; 32]),
+        "alice.near",
+        "other-nft.near",
+        vec![(
+            "nft_transfer_call",
+            json!({ "receiver_id": "receiver.near", "token_id": "1", "msg": "" }),
+        )],
+        json!({ "SuccessValue": "" }),
+    );
+    let resolve_transfer_receipt = fixture_receipt(
+        CryptoHash([3; 32]),
+        "nft.near",
+        "nft.near",
+        vec![(
+            "nft_resolve_transfer",
+            json!({
+                "previous_owner_id": "alice.near",
+                "receiver_id": "receiver.near",
+                "token_id": "2",
+            }),
+        )],
+        json!({ "SuccessValue": BASE64_STANDARD.encode("false") }),
+    );
+    let transaction = fixture_transaction(vec![
+        other_token_receipt,
+        other_contract_receipt,
+        resolve_transfer_receipt.clone(),
+    ]);
+    let refund = NftTransferEvent {
+        authorized_id: None,
+        old_owner_id: "receiver.near".parse().unwrap(),
+        new_owner_id: "alice.near".parse().unwrap(),
+        token_ids: vec!["1".to_owned()],
+        memo: None,
+    };
+
+    let transfer = ExtendedNftTransferEvent::from_event(
+        refund.clone(),
+        &resolve_transfer_receipt,
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(transfer.reverted_transfer_receipt_id, None);
+
+    // The same log emitted outside of nft_resolve_transfer is a regular transfer
+    let transfer = ExtendedNftTransferEvent::from_event(
+        NftTransferEvent {
+            token_ids: vec!["2".to_owned()],
+            ..refund
+        },
+        &fixture_receipt(
+            CryptoHash([4; 32]),
+            "receiver.near",
+            "nft.near",
+            vec![(
+                "nft_transfer",
+                json!({ "receiver_id": "alice.near", "token_id": "2" }),
+            )],
+            json!({ "SuccessValue": "" }),
+        ),
+        &transaction,
+        &NftIndexerConfig::default(),
+    );
+    assert_eq!(transfer.reverted_transfer_receipt_id, None);
+}
+
+fn fixture_context() -> EventContext {
+    EventContext {
+        transaction_id: CryptoHash([1; 32]),
+        receipt_id: CryptoHash([2; 32]),
+        receipt_index: 0,
+        log_index: 0,
+        event_index: 0,
+        block_height: 117_000_000,
+        block_timestamp_nanosec: 1713000000000000000,
+        tx_sender_id: "alice.near".parse().unwrap(),
+        contract_id: "nft.near".parse().unwrap(),
+        spam_score: 0.0,
+    }
+}
+
+fn fixture_mint(token_id: &str) -> ExtendedNftMintEvent {
+    ExtendedNftMintEvent::from_event(NftMintEvent {
+        owner_id: "alice.near".parse().unwrap(),
+        token_ids: vec![token_id.to_owned()],
+        memo: None,
+    })
+}
+
+#[tokio::test]
+async fn fans_out_to_tuples() {
+    let mut handler = (RecordingHandler::new(), RecordingHandler::new());
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    for sink in [&handler.0, &handler.1] {
+        assert_eq!(sink.mints(), vec![(&fixture_mint("1"), &fixture_context())]);
+        assert_eq!(sink.flushed_blocks(), vec![117_000_000]);
+    }
+
+    let mut handler = (RecordingHandler::new(), FailingHandler);
+    let err = handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap_err();
+    assert_eq!(err.sink, "1");
+    assert_eq!(handler.0.mints().len(), 1);
+}
+
+#[tokio::test]
+async fn fan_out_failure_modes() {
+    let mut handler = FanOutHandler::new()
+        .with_sink("failing", FailingHandler, FailureMode::BestEffort)
+        .with_sink("recording", RecordingHandler::new(), FailureMode::FailFast);
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    let mut handler = FanOutHandler::new()
+        .with_sink(
+            "recording",
+            RecordingHandler::new(),
+            FailureMode::BestEffort,
+        )
+        .with_sink("failing", FailingHandler, FailureMode::FailFast);
+    let err = handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap_err();
+    assert_eq!(err.sink, "failing");
+}
+
+#[tokio::test]
+async fn fan_out_retries_failed_flushes() {
+    let context = |block_height| EventContext {
+        block_height,
+        ..fixture_context()
+    };
+    let sink = BufferingHandler::new(2);
+    let flushed = Arc::clone(&sink.flushed);
+    let mut handler = FanOutHandler::new().with_sink("buffering", sink, FailureMode::BestEffort);
+
+    handler
+        .handle_mint(fixture_mint("1"), context(117_000_000))
+        .await
+        .unwrap();
+    // Fails
+    handler.flush_events(117_000_000).await.unwrap();
+    // The retry fails too, so the mint of this block is dropped
+    handler
+        .handle_mint(fixture_mint("2"), context(117_000_001))
+        .await
+        .unwrap();
+    handler.flush_events(117_000_001).await.unwrap();
+    handler
+        .handle_mint(fixture_mint("3"), context(117_000_002))
+        .await
+        .unwrap();
+    handler.flush_events(117_000_002).await.unwrap();
+
+    let flushed = flushed.lock().unwrap();
+    flushed.assert_flushed_in_order();
+    assert_eq!(
+        flushed.events,
+        vec![
+            RecordedEvent::Mint(fixture_mint("1"), context(117_000_000)),
+            RecordedEvent::Flush(117_000_000),
+            RecordedEvent::Flush(117_000_001),
+            RecordedEvent::Mint(fixture_mint("3"), context(117_000_002)),
+            RecordedEvent::Flush(117_000_002),
+        ]
+    );
+}
+
+#[test]
+fn account_filter_patterns() {
+    let filter = AccountFilter {
+        allow: vec!["*.mintbase1.near".to_owned(), "x.paras.near".to_owned()].into(),
+        deny: vec!["spam.mintbase1.near".to_owned()].into(),
+    };
+    assert!(filter.matches(&"beanlabs.mintbase1.near".parse().unwrap()));
+    assert!(filter.matches(&"x.paras.near".parse().unwrap()));
+    assert!(!filter.matches(&"mintbase1.near".parse().unwrap()));
+    assert!(!filter.matches(&"spam.mintbase1.near".parse().unwrap()));
+    assert!(!filter.matches(&"claim.sharddog.near".parse().unwrap()));
+
+    let filter = AccountFilter::default();
+    assert!(filter.matches(&"claim.sharddog.near".parse().unwrap()));
+    assert!(filter.matches_any([]));
+}
+
+#[tokio::test]
+async fn filters_events() {
+    let mut handler = FilteredHandler::new(
+        RecordingHandler::new(),
+        EventFilter {
+            contracts: AccountFilter {
+                allow: vec!["*.near".to_owned()].into(),
+                ..Default::default()
+            },
+            owners: AccountFilter {
+                deny: vec!["bob.near".to_owned()].into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler
+        .handle_mint(
+            ExtendedNftMintEvent::from_event(NftMintEvent {
+                owner_id: "bob.near".parse().unwrap(),
+                token_ids: vec!["2".to_owned()],
+                memo: None,
+            }),
+            fixture_context(),
+        )
+        .await
+        .unwrap();
+    handler
+        .handle_mint(
+            fixture_mint("3"),
+            EventContext {
+                contract_id: "nft.testnet".parse().unwrap(),
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    assert_eq!(
+        handler.handler.mints(),
+        vec![(&fixture_mint("1"), &fixture_context())]
+    );
+    assert_eq!(handler.handler.flushed_blocks(), vec![117_000_000]);
+}
+
+#[test]
+fn scores_spam() {
+    let config = SpamConfig {
+        mass_mint_threshold: 3,
+        drop_threshold: Some(0.7),
+        traded_contracts: Some(HashSet::from_iter(["old.near".parse().unwrap()])),
+        ..Default::default()
+    };
+    let mut classifier = SpamClassifier::new();
+    let spammer: AccountId = "spammer.near".parse().unwrap();
+    let airdrop: AccountId = "free-airdrop.near".parse().unwrap();
+    let collection: AccountId = "nft.near".parse().unwrap();
+
+    for owner in ["alice.near", "bob.near", "carol.near"] {
+        classifier.record_mint(
+            CryptoHash([1; 32]),
+            &airdrop,
+            &spammer,
+            &owner.parse().unwrap(),
+        );
+    }
+    // Minting to yourself is never unsolicited
+    for _ in 0..3 {
+        classifier.record_mint(CryptoHash([2; 32]), &collection, &spammer, &spammer);
+    }
+    classifier.record_trade(&collection);
+
+    let score = classifier.score(&config, CryptoHash([1; 32]), &airdrop);
+    assert_eq!(score, 1.0);
+    assert!(config.should_drop(score));
+    assert_eq!(
+        classifier.score(&config, CryptoHash([2; 32]), &collection),
+        0.0
+    );
+    assert_eq!(
+        classifier.score(&config, CryptoHash([2; 32]), &"new.near".parse().unwrap()),
+        0.2
+    );
+    assert_eq!(
+        classifier.score(&config, CryptoHash([2; 32]), &"old.near".parse().unwrap()),
+        0.0
+    );
+
+    // Without known trades, every contract would look never traded after a restart
+    let unseeded_config = SpamConfig {
+        traded_contracts: None,
+        ..config.clone()
+    };
+    assert_eq!(
+        classifier.score(&unseeded_config, CryptoHash([1; 32]), &airdrop),
+        0.8
+    );
+    assert_eq!(
+        classifier.score(
+            &unseeded_config,
+            CryptoHash([2; 32]),
+            &"new.near".parse().unwrap()
+        ),
+        0.0
+    );
+
+    classifier.end_block();
+    assert_eq!(
+        classifier.score(&config, CryptoHash([1; 32]), &airdrop),
+        0.5
+    );
+}
+
+#[test]
+fn detects_wash_trades() {
+    const DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+    let config = WashTradeConfig::default();
+    let contract_id: AccountId = "nft.near".parse().unwrap();
+    let trade_event = |token_id: &str, seller: &str, buyer: &str, royalty_share: Balance| {
+        let trade = TokenTrade {
+            buyer_id: buyer.parse().unwrap(),
+            ..fixture_trade(NEAR, Some((NEAR - royalty_share, royalty_share)))
+        };
+        ExtendedNftTransferEvent {
+            event: NftTransferEvent {
+                old_owner_id: seller.parse().unwrap(),
+                new_owner_id: buyer.parse().unwrap(),
+                ..fixture_transfer(&[token_id])
+            },
+            trade: NftTradeDetails {
+                token_prices_near: vec![Some(trade.price)],
+                token_trades: vec![Some(trade)],
+                payout_anomalies: vec![],
+            },
+            reverted_transfer_receipt_id: None,
+            suspected_wash_trade: false,
+        }
+    };
+
+    let mut analyzer = WashTradeAnalyzer::new();
+    assert!(!analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("1", "seller.near", "buyer.near", 0),
+        0
+    ));
+    // Back to the seller the next day
+    assert!(analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("1", "buyer.near", "seller.near", 0),
+        DAY
+    ));
+    // And again, after the window
+    analyzer.end_block(&config, 30 * DAY);
+    assert!(!analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("1", "seller.near", "buyer.near", 0),
+        30 * DAY
+    ));
+
+    // Buyer and seller created by the same account
+    analyzer.record_funding(
+        &config,
+        &"funder.near".parse().unwrap(),
+        &"alice.near".parse().unwrap(),
+    );
+    analyzer.record_funding(
+        &config,
+        &"funder.near".parse().unwrap(),
+        &"bob.near".parse().unwrap(),
+    );
+    assert!(analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("2", "alice.near", "bob.near", 0),
+        30 * DAY
+    ));
+
+    // Royalties go to an account created by the seller, so the seller gets all
+    // of the payout back
+    analyzer.record_funding(
+        &config,
+        &"seller.near".parse().unwrap(),
+        &"creator.near".parse().unwrap(),
+    );
+    assert!(analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("3", "seller.near", "buyer.near", NEAR / 10 * 9),
+        30 * DAY
+    ));
+    // Royalties go to someone else, or there are none
+    assert!(!analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("4", "carol.near", "dave.near", NEAR / 10),
+        30 * DAY
+    ));
+    assert!(!analyzer.analyze(
+        &config,
+        &contract_id,
+        &trade_event("5", "carol.near", "dave.near", 0),
+        30 * DAY
+    ));
+}
+
+#[tokio::test]
+async fn detects_wash_trades_in_transactions() {
+    // alice.near was created before the indexer started, bob.whale.near while it runs
+    let config = NftIndexerConfig {
+        wash_trades: Some(WashTradeConfig {
+            funders: HashMap::from_iter([(
+                "alice.near".parse().unwrap(),
+                "whale.near".parse().unwrap(),
+            )]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut indexer = NftIndexer::new(RecordingHandler::new()).with_config(config);
+
+    let mut create_account = fixture_receipt(
+        CryptoHash([1; 32]),
+        "whale.near",
+        "bob.whale.near",
+        vec![],
+        json!({ "SuccessValue": "" }),
+    );
+    if let ReceiptEnumView::Action { actions, .. } = &mut create_account.receipt.receipt.receipt {
+        *actions = vec![
+            ActionView::CreateAccount,
+            ActionView::Transfer { deposit: NEAR },
+        ];
+    }
+    let mut transaction = fixture_transaction(vec![create_account.clone()]);
+    transaction.transaction.transaction.signer_id = "whale.near".parse().unwrap();
+    indexer
+        .process_receipt(&create_account, &transaction)
+        .await
+        .unwrap();
+
+    // A marketplace purchase of token `token_id` with the payout in `payout`
+    let purchase = |receipt_id: u8,
+                    token_id: &str,
+                    seller: &str,
+                    buyer: &str,
+                    payout: serde_json::Value| {
+        let mut args = payout_args(token_id, NEAR);
+        args["receiver_id"] = json!(buyer);
+        let mut receipt = fixture_receipt(
+            CryptoHash([receipt_id; 32]),
+            "marketplace.near",
+            "nft.near",
+            vec![("nft_transfer_payout", args)],
+            json!({
+                "SuccessValue": BASE64_STANDARD.encode(json!({ "payout": payout }).to_string())
+            }),
+        );
+        receipt.receipt.execution_outcome.outcome.logs = vec![format!(
+            r#"EVENT_JSON:{{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{{"authorized_id":"marketplace.near","old_owner_id":"{seller}","new_owner_id":"{buyer}","token_ids":["{token_id}"]}}]}}"#
+        )];
+        let mut transaction = fixture_transaction(vec![receipt.clone()]);
+        transaction.transaction.transaction.signer_id = buyer.parse().unwrap();
+        (receipt, transaction)
+    };
+    let purchases = [
+        // Both accounts were created by whale.near
+        purchase(
+            2,
+            "1",
+            "alice.near",
+            "bob.whale.near",
+            json!({ "alice.near": (NEAR * 9 / 10).to_string(), "creator.near": (NEAR / 10).to_string() }),
+        ),
+        // Royalties go to an account created by the seller
+        purchase(
+            3,
+            "2",
+            "whale.near",
+            "dave.near",
+            json!({ "whale.near": (NEAR * 9 / 10).to_string(), "bob.whale.near": (NEAR / 10).to_string() }),
+        ),
+        // An ordinary sale
+        purchase(
+            4,
+            "3",
+            "carol.near",
+            "dave.near",
+            json!({ "carol.near": (NEAR * 9 / 10).to_string(), "creator.near": (NEAR / 10).to_string() }),
+        ),
+        // Sold back to alice.near right away
+        purchase(
+            5,
+            "1",
+            "bob.whale.near",
+            "alice.near",
+            json!({ "bob.whale.near": NEAR.to_string() }),
+        ),
+    ];
+    for (receipt, transaction) in &purchases {
+        indexer.process_receipt(receipt, transaction).await.unwrap();
+    }
+
+    assert_eq!(
+        indexer
+            .handler
+            .transfers()
+            .into_iter()
+            .map(|(transfer, _)| {
+                assert!(transfer.trade.token_trades[0].is_some());
+                transfer.suspected_wash_trade
+            })
+            .collect::<Vec<_>>(),
+        vec![true, true, false, true]
+    );
+}
+
+#[tokio::test]
+async fn serves_metrics_split_across_packets() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(metrics::serve_listener(listener));
+    metrics::record_block_height(117_000_000);
+
+    let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+    socket.write_all(b"GET /metr").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    socket
+        .write_all(b"ics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("nft_block_height"), "{response}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn closes_idle_metrics_connections() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(metrics::serve_listener(listener));
+
+    let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+    socket
+        .write_all(b"GET /metrics HTTP/1.1\r\n")
+        .await
+        .unwrap();
+    // The paused clock skips ahead to the timeout while nothing else is sent
+    let mut response = Vec::new();
+    socket.read_to_end(&mut response).await.unwrap();
+    assert!(response.is_empty());
+}
+
+#[test]
+fn bounds_contract_labels() {
+    let first: AccountId = "first-labeled.near".parse().unwrap();
+    assert_eq!(metrics::contract_label(&first), "first-labeled.near");
+    for i in 0..1000 {
+        metrics::contract_label(&format!("contract{i}.near").parse().unwrap());
+    }
+    assert_eq!(metrics::contract_label(&first), "first-labeled.near");
+    assert_eq!(
+        metrics::contract_label(&"last-labeled.near".parse().unwrap()),
+        "other"
+    );
+}
+
+#[test]
+fn detects_invalid_logs() {
+    let reason = |log: &str| NftInvalidLog::from_log(log).map(|invalid_log| invalid_log.reason);
+
+    assert_eq!(
+        reason(
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"alice.near","token_ids":["1"]}]}"#
+        ),
+        None
+    );
+    assert_eq!(reason("Transferred 1 nep171 token"), None);
+    assert_eq!(
+        reason(r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[]}"#),
+        None
+    );
+    assert_eq!(
+        reason(r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[}"#),
+        Some(InvalidLogReason::BadJson)
+    );
+    assert_eq!(
+        reason(
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_approve","data":[]}"#
+        ),
+        Some(InvalidLogReason::UnknownEvent)
+    );
+    assert_eq!(
+        reason(
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.2.0","event":"contract_metadata_update","data":[{}]}"#
+        ),
+        None
+    );
+    assert_eq!(
+        reason(
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"contract_metadata_update","data":[{}]}"#
+        ),
+        Some(InvalidLogReason::WrongVersion)
+    );
+    assert_eq!(
+        reason(
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner":"alice.near","token_ids":["1"]}]}"#
+        ),
+        Some(InvalidLogReason::ValidationFailure)
+    );
+}
+
+#[test]
+fn counts_invalid_logs_by_event_and_reason() {
+    let validation_failures = |event: &str, reason: &str| {
+        metrics::LOG_VALIDATION_FAILURES
+            .with_label_values(&[event, reason])
+            .get()
+    };
+    let wrong_version = validation_failures("contract_metadata_update", "wrong_version");
+    let bad_data = validation_failures("nft_burn", "validation_failure");
+    let parse_failures = metrics::LOG_PARSE_FAILURES.get();
+
+    for log in [
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"contract_metadata_update","data":[{}]}"#,
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_burn","data":[{"owner":"alice.near","token_ids":["1"]}]}"#,
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_approve","data":[]}"#,
+    ] {
+        metrics::record_invalid_log(&NftInvalidLog::from_log(log).unwrap());
+    }
+
+    assert_eq!(
+        validation_failures("contract_metadata_update", "wrong_version"),
+        wrong_version + 1
+    );
+    assert_eq!(
+        validation_failures("nft_burn", "validation_failure"),
+        bad_data + 1
+    );
+    assert!(metrics::LOG_PARSE_FAILURES.get() > parse_failures);
+}
+
+#[test]
+fn dispatches_on_event() {
+    let Some(Ok(NftEventLog::Burn(burn_log))) = parse_event_log(
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_burn","data":[{"owner_id":"alice.near","token_ids":["1","2"]}]}"#,
+    ) else {
+        panic!("Expected a burn log");
+    };
+    assert_eq!(burn_log.data.0[0].token_ids, vec!["1", "2"]);
+    assert!(matches!(
+        parse_event_log(
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.1.0","event":"nft_metadata_update","data":[{"token_ids":["1"]}]}"#
+        ),
+        Some(Ok(NftEventLog::MetadataUpdate(_)))
+    ));
+}
+
+#[tokio::test]
+async fn dispatches_metadata_updates() {
+    let mut receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "alice.near",
+        "nft.near",
+        vec![("nft_reveal", json!({ "token_ids": ["1", "2"] }))],
+        json!({ "SuccessValue": "" }),
+    );
+    // As emitted by contracts that follow the examples in NEP-171
+    receipt.receipt.execution_outcome.outcome.logs = vec![
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.1.0","event":"nft_metadata_update","data":[{"token_ids":["1","2"],"memo":"Revealed"}]}"#.to_owned(),
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.2.0","event":"contract_metadata_update","data":[{}]}"#.to_owned(),
+    ];
+    let transaction = fixture_transaction(vec![receipt.clone()]);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+
+    let context = EventContext {
+        transaction_id: CryptoHash::default(),
+        receipt_id: CryptoHash([1; 32]),
+        receipt_index: 0,
+        log_index: 0,
+        event_index: 0,
+        block_height: 117_000_000,
+        block_timestamp_nanosec: 1713000000000000000,
+        tx_sender_id: "alice.near".parse().unwrap(),
+        contract_id: "nft.near".parse().unwrap(),
+        spam_score: 0.0,
+    };
+    assert_eq!(
+        indexer.handler.metadata_updates(),
+        vec![(
+            &ExtendedNftMetadataUpdateEvent {
+                event: NftMetadataUpdateEvent {
+                    token_ids: vec!["1".to_owned(), "2".to_owned()],
+                    memo: Some("Revealed".to_owned()),
+                }
+            },
+            &context
+        )]
+    );
+    assert_eq!(
+        indexer.handler.contract_metadata_updates(),
+        vec![(
+            &ExtendedNftContractMetadataUpdateEvent {
+                event: NftContractMetadataUpdateEvent { memo: None }
+            },
+            &EventContext {
+                log_index: 1,
+                ..context
+            }
+        )]
+    );
+    assert!(indexer.handler.invalid_logs().is_empty());
+}
+
+#[tokio::test]
+async fn passes_events_of_a_receipt_in_order() {
+    let mut receipt = fixture_receipt(
+        CryptoHash([1; 32]),
+        "alice.near",
+        "nft.near",
+        vec![("nft_batch_mint", json!({}))],
+        json!({ "SuccessValue": "" }),
+    );
+    receipt.receipt.execution_outcome.outcome.logs = vec![
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"alice.near","token_ids":["1"]},{"owner_id":"bob.near","token_ids":["2"]}]}"#.to_owned(),
+        "Minted 2 tokens".to_owned(),
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_approve","data":[]}"#.to_owned(),
+        r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_burn","data":[{"owner_id":"alice.near","token_ids":["1"]}]}"#.to_owned(),
+    ];
+    let transaction = fixture_transaction(vec![receipt.clone()]);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
+    indexer
+        .process_receipt(&receipt, &transaction)
+        .await
+        .unwrap();
+    indexer.handler.flush_events(117_000_000).await.unwrap();
+
+    let positions = indexer
+        .handler
+        .events
+        .iter()
+        .filter_map(|event| event.context())
+        .map(|context| (context.log_index, context.event_index))
+        .collect::<Vec<_>>();
+    assert_eq!(positions, vec![(0, 0), (0, 1), (2, 0), (3, 0)]);
+    indexer.handler.assert_flushed_in_order();
+}
+
+#[tokio::test]
+async fn passes_events_of_a_block_in_order() {
+    let receipt = |receipt_id, logs: &[&str]| {
+        let mut receipt = fixture_receipt(
+            receipt_id,
+            "alice.near",
+            "nft.near",
+            vec![("nft_batch_mint", json!({}))],
+            json!({ "SuccessValue": "" }),
+        );
+        receipt.receipt.execution_outcome.outcome.logs =
+            logs.iter().map(|log| log.to_string()).collect();
+        receipt
+    };
+    let first = receipt(
+        CryptoHash([1; 32]),
+        &[
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"alice.near","token_ids":["1"]}]}"#,
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_burn","data":[{"owner_id":"alice.near","token_ids":["1"]}]}"#,
+        ],
+    );
+    let second = receipt(
+        CryptoHash([3; 32]),
+        &[
+            r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"bob.near","token_ids":["2"]},{"owner_id":"bob.near","token_ids":["3"]}]}"#,
+        ],
+    );
+    let transaction = fixture_transaction(vec![first.clone(), second.clone()]);
+    let mut indexer = NftIndexer::new(RecordingHandler::new());
+    // Passed in a different order than the one of the block
+    indexer.queue_receipt(1, &second, &transaction);
+    indexer.queue_receipt(0, &first, &transaction);
+    assert!(indexer.handler.events.is_empty());
+    indexer.process_queued_events().await.unwrap();
+    indexer.handler.flush_events(117_000_000).await.unwrap();
+
+    let positions = indexer
+        .handler
+        .events
+        .iter()
+        .filter_map(|event| event.context())
+        .map(|context| {
+            (
+                context.receipt_id,
+                context.receipt_index,
+                context.log_index,
+                context.event_index,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        positions,
+        vec![
+            (CryptoHash([1; 32]), 0, 0, 0),
+            (CryptoHash([1; 32]), 0, 1, 0),
+            (CryptoHash([3; 32]), 1, 0, 0),
+            (CryptoHash([3; 32]), 1, 0, 1),
+        ]
+    );
+    indexer.handler.assert_flushed_in_order();
+}
+
+#[tokio::test]
+#[should_panic(expected = "was recorded after a later event of the same receipt")]
+async fn asserts_event_order_within_receipt() {
+    let mut handler = RecordingHandler::new();
+    handler
+        .handle_mint(
+            fixture_mint("1"),
+            EventContext {
+                log_index: 1,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler
+        .handle_mint(fixture_mint("2"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    handler.assert_flushed_in_order();
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+#[ignore = "needs a PostgreSQL database at $POSTGRES_TEST_URL"]
+async fn writes_to_postgres() {
+    use nft_indexer::postgres_handler::PushToPostgres;
+
+    let database_url = std::env::var("POSTGRES_TEST_URL").unwrap();
+    // Unique per run, so that the test doesn't see rows from previous runs
+    let receipt_id = CryptoHash(rand::random());
+    let context = EventContext {
+        receipt_id,
+        ..fixture_context()
+    };
+    let mut handler = PushToPostgres::connect(&database_url)
+        .await
+        .unwrap()
+        .with_checkpoint_name(receipt_id.to_string());
+
+    handler
+        .handle_mint(fixture_mint("1"), context.clone())
+        .await
+        .unwrap();
+    handler
+        .handle_transfer(
+            ExtendedNftTransferEvent {
+                event: fixture_transfer(&["1"]),
+                trade: NftTradeDetails {
+                    token_prices_near: vec![Some(NEAR)],
+                    token_trades: vec![Some(fixture_trade(NEAR, Some((NEAR / 10 * 9, NEAR / 10))))],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
+            },
+            EventContext {
+                log_index: 1,
+                ..context.clone()
+            },
+        )
+        .await
+        .unwrap();
+    handler
+        .handle_burn(
+            ExtendedNftBurnEvent::from_event(NftBurnEvent {
+                owner_id: "buyer.near".parse().unwrap(),
+                authorized_id: None,
+                token_ids: vec!["1".to_owned()],
+                memo: None,
+            }),
+            EventContext {
+                log_index: 2,
+                ..context.clone()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    assert_eq!(
+        handler.last_block_height().await.unwrap(),
+        Some(117_000_000)
+    );
+
+    // Re-processing the block doesn't duplicate its rows
+    handler
+        .handle_mint(fixture_mint("1"), context.clone())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    let (client, connection) = tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    let receipt_id = receipt_id.to_string();
+    let mints: Vec<String> = client
+        .query(
+            "SELECT token_id FROM nft_mints WHERE receipt_id = $1",
+            &[&receipt_id],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(mints, vec!["1"]);
+    let transfer = client
+        .query_one(
+            "SELECT old_owner_id, new_owner_id, log_index FROM nft_transfers WHERE receipt_id = $1",
+            &[&receipt_id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(transfer.get::<_, String>(0), "seller.near");
+    assert_eq!(transfer.get::<_, String>(1), "buyer.near");
+    assert_eq!(transfer.get::<_, i32>(2), 1);
+    let trade = client
+        .query_one(
+            "SELECT price::TEXT, currency, seller_proceeds::TEXT, marketplace_fee::TEXT
+            FROM nft_trades WHERE receipt_id = $1",
+            &[&receipt_id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(trade.get::<_, String>(0), NEAR.to_string());
+    assert_eq!(trade.get::<_, String>(1), "near");
+    assert_eq!(
+        trade.get::<_, Option<String>>(2),
+        Some((NEAR / 10 * 9).to_string())
+    );
+    assert_eq!(trade.get::<_, Option<String>>(3), None);
+    let royalty = client
+        .query_one(
+            "SELECT account_id, amount::TEXT FROM nft_trade_royalties WHERE receipt_id = $1",
+            &[&receipt_id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(royalty.get::<_, String>(0), "creator.near");
+    assert_eq!(royalty.get::<_, String>(1), (NEAR / 10).to_string());
+    let burns = client
+        .query(
+            "SELECT token_id FROM nft_burns WHERE receipt_id = $1",
+            &[&receipt_id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(burns.len(), 1);
+}
+
+#[cfg(feature = "postgres")]
+#[tokio::test]
+#[ignore = "needs a PostgreSQL database at $POSTGRES_TEST_URL"]
+async fn backfills_postgres_below_checkpoint() {
+    use nft_indexer::postgres_handler::PushToPostgres;
+
+    let database_url = std::env::var("POSTGRES_TEST_URL").unwrap();
+    let receipt_id = CryptoHash(rand::random());
+    let mut handler = PushToPostgres::connect(&database_url)
+        .await
+        .unwrap()
+        .with_checkpoint_name(receipt_id.to_string());
+
+    handler
+        .handle_mint(
+            fixture_mint("1"),
+            EventContext {
+                receipt_id,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    // A backfill of earlier blocks after the indexer already got further
+    handler
+        .handle_mint(
+            fixture_mint("2"),
+            EventContext {
+                receipt_id,
+                log_index: 1,
+                block_height: 116_000_000,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler
+        .handle_burn(
+            ExtendedNftBurnEvent::from_event(NftBurnEvent {
+                owner_id: "alice.near".parse().unwrap(),
+                authorized_id: None,
+                token_ids: vec!["2".to_owned()],
+                memo: None,
+            }),
+            EventContext {
+                receipt_id,
+                log_index: 2,
+                block_height: 116_000_000,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(116_000_000).await.unwrap();
+    // Re-processing the block at the checkpoint doesn't write it twice
+    handler
+        .handle_mint(
+            fixture_mint("1"),
+            EventContext {
+                receipt_id,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    assert_eq!(
+        handler.last_block_height().await.unwrap(),
+        Some(117_000_000)
+    );
+    let (client, connection) = tokio_postgres::connect(&database_url, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    let mints: Vec<(String, i64)> = client
+        .query(
+            "SELECT token_id, block_height FROM nft_mints WHERE receipt_id = $1 ORDER BY token_id",
+            &[&receipt_id.to_string()],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    assert_eq!(
+        mints,
+        vec![("1".to_owned(), 117_000_000), ("2".to_owned(), 116_000_000)]
+    );
+    let burns: Vec<(String, i64)> = client
+        .query(
+            "SELECT token_id, block_height FROM nft_burns WHERE receipt_id = $1",
+            &[&receipt_id.to_string()],
+        )
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    assert_eq!(burns, vec![("2".to_owned(), 116_000_000)]);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn writes_to_sqlite() {
+    use nft_indexer::sqlite_handler::PushToSqlite;
+
+    let path =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}.sqlite", rand::random::<u64>()));
+    let mut handler = PushToSqlite::open(&path).unwrap();
+    assert_eq!(handler.last_block_height().unwrap(), None);
+
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler
+        .handle_mint(
+            fixture_mint("2"),
+            EventContext {
+                log_index: 1,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler
+        .handle_transfer(
+            ExtendedNftTransferEvent {
+                event: fixture_transfer(&["1"]),
+                trade: NftTradeDetails {
+                    token_prices_near: vec![Some(NEAR)],
+                    token_trades: vec![Some(fixture_trade(NEAR, Some((NEAR / 10 * 9, NEAR / 10))))],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
+            },
+            EventContext {
+                log_index: 2,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler
+        .handle_burn(
+            ExtendedNftBurnEvent::from_event(NftBurnEvent {
+                owner_id: "alice.near".parse().unwrap(),
+                authorized_id: None,
+                token_ids: vec!["2".to_owned()],
+                memo: None,
+            }),
+            EventContext {
+                log_index: 3,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    assert_eq!(handler.last_block_height().unwrap(), Some(117_000_000));
+
+    // Re-processing the block after a restart doesn't write it twice
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let count = |table: &str| -> i64 {
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    };
+    assert_eq!(count("nft_mints"), 2);
+    assert_eq!(count("nft_transfers"), 1);
+    assert_eq!(count("nft_burns"), 1);
+    let (price, seller_proceeds): (String, Option<String>) = connection
+        .query_row(
+            "SELECT price, seller_proceeds FROM nft_trades WHERE token_id = '1'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(price, NEAR.to_string());
+    assert_eq!(seller_proceeds, Some((NEAR / 10 * 9).to_string()));
+    let royalty: (String, String) = connection
+        .query_row(
+            "SELECT account_id, amount FROM nft_trade_royalties",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        royalty,
+        ("creator.near".to_owned(), (NEAR / 10).to_string())
+    );
+    let owners: Vec<(String, String, String)> = connection
+        .prepare("SELECT contract_id, token_id, owner_id FROM nft_owners")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        owners,
+        vec![(
+            "nft.near".to_owned(),
+            "1".to_owned(),
+            "buyer.near".to_owned()
+        )]
+    );
+
+    drop(handler);
+    drop(connection);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn backfills_sqlite_below_checkpoint() {
+    use nft_indexer::sqlite_handler::PushToSqlite;
+
+    let path =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}.sqlite", rand::random::<u64>()));
+    let mut handler = PushToSqlite::open(&path).unwrap();
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    let backfill_context = |log_index| EventContext {
+        receipt_id: CryptoHash([3; 32]),
+        log_index,
+        block_height: 116_000_000,
+        ..fixture_context()
+    };
+    handler
+        .handle_mint(fixture_mint("2"), backfill_context(0))
+        .await
+        .unwrap();
+    handler
+        .handle_transfer(
+            ExtendedNftTransferEvent {
+                event: fixture_transfer(&["1"]),
+                trade: NftTradeDetails {
+                    token_prices_near: vec![None],
+                    token_trades: vec![None],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
+            },
+            backfill_context(1),
+        )
+        .await
+        .unwrap();
+    handler.flush_events(116_000_000).await.unwrap();
+    assert_eq!(handler.last_block_height().unwrap(), Some(117_000_000));
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let mints: Vec<(String, i64)> = connection
+        .prepare("SELECT token_id, block_height FROM nft_mints ORDER BY token_id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        mints,
+        vec![("1".to_owned(), 117_000_000), ("2".to_owned(), 116_000_000)]
+    );
+    let transfers: i64 = connection
+        .query_row("SELECT COUNT(*) FROM nft_transfers", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(transfers, 1);
+    // The older transfer doesn't override the owner set by the newer mint
+    let owners: Vec<(String, String)> = connection
+        .prepare("SELECT token_id, owner_id FROM nft_owners")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(owners, vec![("1".to_owned(), "alice.near".to_owned())]);
+
+    drop(handler);
+    drop(connection);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn rolls_back_sqlite_block_after_an_error() {
+    use nft_indexer::sqlite_handler::PushToSqlite;
+
+    let path =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}.sqlite", rand::random::<u64>()));
+    let mut handler = PushToSqlite::open(&path).unwrap();
+    // Makes the burn below fail after the mint of the same block was written
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection.execute_batch("DROP TABLE nft_burns").unwrap();
+
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    let burn = handler
+        .handle_burn(
+            ExtendedNftBurnEvent::from_event(NftBurnEvent {
+                owner_id: "alice.near".parse().unwrap(),
+                authorized_id: None,
+                token_ids: vec!["1".to_owned()],
+                memo: None,
+            }),
+            EventContext {
+                log_index: 1,
+                ..fixture_context()
+            },
+        )
+        .await;
+    assert!(burn.is_err());
+    drop(handler);
+
+    let count = |table: &str| -> i64 {
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    };
+    assert_eq!(count("nft_mints"), 0);
+    assert_eq!(count("nft_owners"), 0);
+    assert_eq!(count("nft_checkpoints"), 0);
+
+    drop(connection);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn fan_out_disables_sqlite_after_an_error() {
+    use nft_indexer::sqlite_handler::PushToSqlite;
+
+    let path =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}.sqlite", rand::random::<u64>()));
+    let sqlite = PushToSqlite::open(&path).unwrap();
+    // Makes the burn below fail after the mint of the same block was written
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection.execute_batch("DROP TABLE nft_burns").unwrap();
+    let mut handler = FanOutHandler::new()
+        .with_sink("failing", FailingHandler, FailureMode::BestEffort)
+        .with_sink("sqlite", sqlite, FailureMode::BestEffort);
+
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler
+        .handle_burn(
+            ExtendedNftBurnEvent::from_event(NftBurnEvent {
+                owner_id: "alice.near".parse().unwrap(),
+                authorized_id: None,
+                token_ids: vec!["1".to_owned()],
+                memo: None,
+            }),
+            EventContext {
+                log_index: 1,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    handler
+        .handle_mint(
+            fixture_mint("2"),
+            EventContext {
+                block_height: 117_000_001,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_001).await.unwrap();
+
+    // The mint of the failed block isn't committed without its burn
+    let count = |table: &str| -> i64 {
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    };
+    assert_eq!(count("nft_mints"), 0);
+    assert_eq!(count("nft_checkpoints"), 0);
+
+    drop(handler);
+    drop(connection);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "kafka")]
+#[tokio::test]
+async fn builds_kafka_messages_from_intear_events() {
+    use intear_events::events::nft::nft_mint;
+    use nft_indexer::kafka_handler::{KafkaMessage, KafkaTopics, PushToKafka};
+
+    // Nothing is sent until the flush, so no broker is needed
+    let mut handler = PushToKafka::connect("127.0.0.1:9")
+        .unwrap()
+        .with_topics(KafkaTopics::prefixed("test-"));
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+
+    let [message] = handler.messages() else {
+        panic!("Expected one message, got {:?}", handler.messages());
+    };
+    assert_eq!(message.topic(), "test-nft_mint");
+    assert_eq!(message.key(), "nft.near");
+    assert_eq!(message.timestamp_millis(), 1713000000000);
+    let mint: nft_mint::NftMintEvent = serde_json::from_str(message.payload()).unwrap();
+    assert_eq!(mint.token_ids, vec!["1"]);
+    assert_eq!(mint.owner_id.as_str(), "alice.near");
+    assert_eq!(mint.receipt_id, fixture_context().receipt_id);
+    assert_eq!(
+        message,
+        &KafkaMessage::new(
+            "test-nft_mint",
+            &"nft.near".parse().unwrap(),
+            1713000000000000000,
+            &nft_indexer::stream_events::NftMintEvent::new(fixture_mint("1"), fixture_context()),
+        )
+    );
+}
+
+#[cfg(feature = "kafka")]
+#[tokio::test]
+#[ignore = "needs a Kafka broker at $KAFKA_TEST_BROKERS"]
+async fn publishes_to_kafka() {
+    use nft_indexer::kafka_handler::{KafkaTopics, PushToKafka};
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::{ClientConfig, Message};
+
+    let brokers = std::env::var("KAFKA_TEST_BROKERS").unwrap();
+    // Unique per run, so that the test doesn't see messages from previous runs
+    let topics = KafkaTopics::prefixed(&format!("test-{}-", rand::random::<u32>()));
+    let mut handler = PushToKafka::connect(&brokers)
+        .unwrap()
+        .with_topics(topics.clone());
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", &topics.mint)
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .unwrap();
+    consumer.subscribe(&[&topics.mint]).unwrap();
+    let message = consumer
+        .poll(Duration::from_secs(30))
+        .expect("No message received")
+        .unwrap();
+    assert_eq!(message.key(), Some("nft.near".as_bytes()));
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(message.payload().unwrap()).unwrap(),
+        serde_json::to_value(nft_indexer::stream_events::NftMintEvent::new(
+            fixture_mint("1"),
+            fixture_context()
+        ))
+        .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn writes_jsonl_files() {
+    use nft_indexer::jsonl_handler::{PushToJsonlFiles, RotationPolicy};
+
+    let directory =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}", rand::random::<u64>()));
+    let rotation = RotationPolicy {
+        blocks_per_file: Some(10),
+        max_file_size: None,
+    };
+    let mut handler = PushToJsonlFiles::open(&directory)
+        .unwrap()
+        .with_rotation(rotation.clone());
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    handler.flush_events(117_000_005).await.unwrap();
+    handler
+        .handle_mint(
+            fixture_mint("2"),
+            EventContext {
+                block_height: 117_000_010,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_010).await.unwrap();
+    assert_eq!(handler.manifest().len(), 1);
+    let first_file = &handler.manifest()[0];
+    assert_eq!(first_file.file, "nft-events-117000000-117000005.jsonl");
+    assert_eq!(
+        (first_file.first_block_height, first_file.last_block_height),
+        (117_000_000, 117_000_005)
+    );
+    assert_eq!(first_file.events, 1);
+    let line: serde_json::Value = serde_json::from_str(
+        std::fs::read_to_string(directory.join(&first_file.file))
+            .unwrap()
+            .trim_end(),
+    )
+    .unwrap();
+    assert_eq!(line["event"], "nft_mint");
+    assert_eq!(line["context"]["block_height"], 117_000_000);
+    assert_eq!(line["data"]["event"]["token_ids"], json!(["1"]));
+
+    // Killed while writing block 117_000_012, the next run finishes the file
+    // without that block and writes it again
+    for token_id in ["3", "4"] {
+        handler
+            .handle_mint(
+                fixture_mint(token_id),
+                EventContext {
+                    block_height: 117_000_012,
+                    ..fixture_context()
+                },
+            )
+            .await
+            .unwrap();
+    }
+    handler.flush_events(117_000_012).await.unwrap();
+    drop(handler);
+    let partial_path = directory.join("nft-events-117000010.jsonl.part");
+    let mut partial_file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&partial_path)
+        .unwrap();
+    std::io::Write::write_all(&mut partial_file, br#"{"event":"nft_mint","context":{"#).unwrap();
+    drop(partial_file);
+
+    let mut handler = PushToJsonlFiles::open(&directory)
+        .unwrap()
+        .with_rotation(rotation);
+    assert!(!partial_path.exists());
+    assert_eq!(handler.manifest().len(), 2);
+    let recovered_file = &handler.manifest()[1];
+    assert_eq!(recovered_file.file, "nft-events-117000010-117000010.jsonl");
+    assert_eq!(recovered_file.events, 1);
+    let recovered_lines = std::fs::read_to_string(directory.join(&recovered_file.file)).unwrap();
+    assert_eq!(recovered_lines.lines().count(), 1);
+    assert!(recovered_lines.ends_with('\n'));
+    assert_eq!(recovered_file.size, recovered_lines.len() as u64);
+
+    for token_id in ["3", "4"] {
+        handler
+            .handle_mint(
+                fixture_mint(token_id),
+                EventContext {
+                    block_height: 117_000_012,
+                    ..fixture_context()
+                },
+            )
+            .await
+            .unwrap();
+    }
+    handler.flush_events(117_000_012).await.unwrap();
+    handler.close().unwrap();
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(directory.join("manifest.json")).unwrap()).unwrap();
+    let files = manifest["files"].as_array().unwrap();
+    assert_eq!(files.len(), 3);
+    assert_eq!(files[2]["file"], "nft-events-117000012-117000012.jsonl");
+    assert_eq!(files[2]["events"], 2);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn resumes_jsonl_files_from_a_later_block() {
+    use nft_indexer::jsonl_handler::{PushToJsonlFiles, RotationPolicy};
+
+    let directory =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}", rand::random::<u64>()));
+    let rotation = RotationPolicy {
+        blocks_per_file: Some(10),
+        max_file_size: None,
+    };
+    let mut handler = PushToJsonlFiles::open(&directory)
+        .unwrap()
+        .with_rotation(rotation.clone());
+    for (token_id, block_height) in [("1", 117_000_000), ("2", 117_000_001)] {
+        handler
+            .handle_mint(
+                fixture_mint(token_id),
+                EventContext {
+                    block_height,
+                    ..fixture_context()
+                },
+            )
+            .await
+            .unwrap();
+        handler.flush_events(block_height).await.unwrap();
+    }
+    // Killed in the middle of writing block 117_000_002
+    drop(handler);
+    let partial_path = directory.join("nft-events-117000000.jsonl.part");
+    let mut partial_file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&partial_path)
+        .unwrap();
+    std::io::Write::write_all(
+        &mut partial_file,
+        br#"{"event":"nft_mint","context":{"block_height":117000002"#,
+    )
+    .unwrap();
+    drop(partial_file);
+
+    // The next run starts after the blocks that were cut off
+    let mut handler = PushToJsonlFiles::open(&directory)
+        .unwrap()
+        .with_rotation(rotation);
+    assert!(!partial_path.exists());
+    handler
+        .handle_mint(
+            fixture_mint("3"),
+            EventContext {
+                block_height: 117_000_020,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_020).await.unwrap();
+    handler.close().unwrap();
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(directory.join("manifest.json")).unwrap()).unwrap();
+    let files = manifest["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| {
+            (
+                file["file"].as_str().unwrap(),
+                file["events"].as_u64().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        files,
+        vec![
+            ("nft-events-117000000-117000000.jsonl", 1),
+            ("nft-events-117000020-117000020.jsonl", 1),
+        ]
+    );
+    let token_ids = |file: &str| {
+        std::fs::read_to_string(directory.join(file))
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let line: serde_json::Value = serde_json::from_str(line).unwrap();
+                line["data"]["event"]["token_ids"].clone()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        token_ids("nft-events-117000000-117000000.jsonl"),
+        vec![json!(["1"])]
+    );
+    assert_eq!(
+        token_ids("nft-events-117000020-117000020.jsonl"),
+        vec![json!(["3"])]
+    );
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn writes_parquet_files() {
+    use arrow_array::{Array, StringArray};
+    use nft_indexer::parquet_handler::PushToParquet;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    const DAY_NANOSEC: u128 = 24 * 60 * 60 * 1_000_000_000;
+
+    let directory =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}", rand::random::<u64>()));
+    let mut handler = PushToParquet::new(&directory);
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    let mints = directory.join("nft_mints/date=2024-04-13/contract=nft.near");
+    assert!(!mints.exists());
+
+    // The first transfer of the next day finishes the mints of the previous one
+    handler
+        .handle_transfer(
+            ExtendedNftTransferEvent {
+                event: fixture_transfer(&["1"]),
+                trade: NftTradeDetails {
+                    token_prices_near: vec![Some(NEAR)],
+                    token_trades: vec![Some(fixture_trade(NEAR, Some((NEAR / 10 * 9, NEAR / 10))))],
+                    payout_anomalies: vec![],
+                },
+                reverted_transfer_receipt_id: None,
+                suspected_wash_trade: false,
+            },
+            EventContext {
+                block_height: 117_086_400,
+                block_timestamp_nanosec: fixture_context().block_timestamp_nanosec + DAY_NANOSEC,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_086_400).await.unwrap();
+    assert!(mints.join("117000000-117000000.parquet").exists());
+    handler.close().unwrap();
+
+    let transfers = std::fs::File::open(
+        directory
+            .join("nft_transfers/date=2024-04-14/contract=nft.near/117086400-117086400.parquet"),
+    )
+    .unwrap();
+    let batches = ParquetRecordBatchReaderBuilder::try_new(transfers)
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    assert_eq!(batch.num_rows(), 1);
+    let price = batch
+        .column_by_name("price")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(price.value(0), NEAR.to_string());
+    let new_owner_id = batch
+        .column_by_name("new_owner_id")
+        .unwrap()
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .unwrap();
+    assert_eq!(new_owner_id.value(0), "buyer.near");
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn skips_parquet_rows_written_before_restart() {
+    use arrow_array::{Array, StringArray};
+    use nft_indexer::parquet_handler::PushToParquet;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let directory =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}", rand::random::<u64>()));
+    let mut handler = PushToParquet::new(&directory);
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    // Writes the rows that are still in memory
+    drop(handler);
+
+    // The restarted indexer processes block 117_000_000 again
+    let mut handler = PushToParquet::new(&directory);
+    handler
+        .handle_mint(fixture_mint("1"), fixture_context())
+        .await
+        .unwrap();
+    handler.flush_events(117_000_000).await.unwrap();
+    handler
+        .handle_mint(
+            fixture_mint("2"),
+            EventContext {
+                block_height: 117_000_001,
+                ..fixture_context()
+            },
+        )
+        .await
+        .unwrap();
+    handler.flush_events(117_000_001).await.unwrap();
+    handler.close().unwrap();
+
+    let mints = directory.join("nft_mints/date=2024-04-13/contract=nft.near");
+    let mut files = std::fs::read_dir(&mints)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(
+        files,
+        vec!["117000000-117000000.parquet", "117000001-117000001.parquet"]
+    );
+    let mut token_ids = Vec::new();
+    for file in files {
+        let batches = ParquetRecordBatchReaderBuilder::try_new(
+            std::fs::File::open(mints.join(file)).unwrap(),
+        )
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        for batch in batches {
+            let column = batch
+                .column_by_name("token_id")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            token_ids.extend((0..column.len()).map(|i| column.value(i).to_owned()));
+        }
+    }
+    assert_eq!(token_ids, vec!["1", "2"]);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn splits_parquet_partitions_and_backfills_below_them() {
+    use arrow_array::{Array, StringArray};
+    use nft_indexer::parquet_handler::PushToParquet;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let directory =
+        std::env::temp_dir().join(format!("nft-indexer-test-{}", rand::random::<u64>()));
+    let mint = |token_id, block_height| {
+        (
+            fixture_mint(token_id),
+            EventContext {
+                block_height,
+                ..fixture_context()
+            },
+        )
+    };
+    let mints = directory.join("nft_mints/date=2024-04-13/contract=nft.near");
+    let files = || {
+        let mut files = std::fs::read_dir(&mints)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+
+    let mut handler = PushToParquet::new(&directory).with_max_rows_per_file(2);
+    for (token_id, block_height) in [("1", 117_000_010), ("2", 117_000_011), ("3", 117_000_012)] {
+        let (event, context) = mint(token_id, block_height);
+        handler.handle_mint(event, context).await.unwrap();
+        handler.flush_events(block_height).await.unwrap();
+    }
+    // The first two blocks filled a file, the last one is still in memory
+    assert_eq!(files(), vec!["117000010-117000011.parquet"]);
+    handler.close().unwrap();
+
+    // A backfill of earlier blocks that ends inside the range of the first file
+    let mut handler = PushToParquet::new(&directory).with_max_rows_per_file(2);
+    for (token_id, block_height) in [("0", 117_000_000), ("2", 117_000_011)] {
+        let (event, context) = mint(token_id, block_height);
+        handler.handle_mint(event, context).await.unwrap();
+        handler.flush_events(block_height).await.unwrap();
+    }
+    handler.close().unwrap();
+
+    let files = files();
+    assert_eq!(
+        files,
+        vec![
+            "117000000-117000000.parquet",
+            "117000010-117000011.parquet",
+            "117000012-117000012.parquet",
+        ]
+    );
+    let mut token_ids = Vec::new();
+    for file in files {
+        let batches = ParquetRecordBatchReaderBuilder::try_new(
+            std::fs::File::open(mints.join(file)).unwrap(),
+        )
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+        for batch in batches {
+            let column = batch
+                .column_by_name("token_id")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            token_ids.extend((0..column.len()).map(|i| column.value(i).to_owned()));
+        }
+    }
+    assert_eq!(token_ids, vec!["0", "1", "2", "3"]);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,206 @@
+//! Heuristics for trades between related accounts that only exist to inflate volume
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use inindexer::near_indexer_primitives::types::{AccountId, Balance};
+
+use crate::{ExtendedNftTransferEvent, TokenTrade};
+
+/// How often old owners are removed from [`WashTradeAnalyzer`]
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct WashTradeConfig {
+    /// A trade that sends a token back to an account that sold it less than this
+    /// long ago is suspicious
+    pub return_window: Duration,
+    /// A trade that pays royalties is suspicious if at least this share of the
+    /// payout goes back to the seller or accounts related to the seller, so the
+    /// seller gets the royalties back
+    pub min_payout_share_to_seller: f64,
+    /// How many accounts to remember the funder of. The oldest ones are forgotten first.
+    pub max_tracked_accounts: usize,
+    /// Accounts that create accounts for unrelated users, such as wallets and
+    /// relayers, and shouldn't make those users related to each other
+    pub ignored_funders: HashSet<AccountId>,
+    /// Funders of accounts created before the indexer started, keyed by account.
+    /// Accounts created while the indexer is running are added by [`WashTradeAnalyzer`].
+    pub funders: HashMap<AccountId, AccountId>,
+}
+
+impl Default for WashTradeConfig {
+    fn default() -> Self {
+        Self {
+            return_window: Duration::from_secs(7 * 24 * 60 * 60),
+            min_payout_share_to_seller: 0.99,
+            max_tracked_accounts: 1_000_000,
+            ignored_funders: HashSet::new(),
+            funders: HashMap::new(),
+        }
+    }
+}
+
+/// Remembers recent owners of traded tokens and who created each account. Only
+/// accounts created while the indexer is running or listed in
+/// [`WashTradeConfig::funders`] have a known funder.
+#[derive(Debug, Default)]
+pub struct WashTradeAnalyzer {
+    /// Owners of each traded token, keyed by contract and token ID, with the
+    /// timestamp of the trade that moved the token away from them
+    previous_owners: HashMap<(AccountId, String), Vec<(AccountId, u128)>>,
+    /// Signer of the transaction that created each account, on top of
+    /// [`WashTradeConfig::funders`]
+    funders: HashMap<AccountId, AccountId>,
+    funding_order: VecDeque<AccountId>,
+    last_pruned_nanosec: u128,
+}
+
+impl WashTradeAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_funding(
+        &mut self,
+        config: &WashTradeConfig,
+        funder_id: &AccountId,
+        account_id: &AccountId,
+    ) {
+        if funder_id == account_id
+            || config.ignored_funders.contains(funder_id)
+            || config.funders.contains_key(account_id)
+            || self.funders.contains_key(account_id)
+        {
+            return;
+        }
+        self.funders.insert(account_id.clone(), funder_id.clone());
+        self.funding_order.push_back(account_id.clone());
+        while self.funding_order.len() > config.max_tracked_accounts {
+            if let Some(forgotten) = self.funding_order.pop_front() {
+                self.funders.remove(&forgotten);
+            }
+        }
+    }
+
+    /// Funders of the accounts created since the analyzer was created, to carry
+    /// them over to [`WashTradeConfig::funders`] of the next one
+    pub fn funders(&self) -> &HashMap<AccountId, AccountId> {
+        &self.funders
+    }
+
+    fn funder<'a>(
+        &'a self,
+        config: &'a WashTradeConfig,
+        account_id: &AccountId,
+    ) -> Option<&'a AccountId> {
+        self.funders
+            .get(account_id)
+            .or_else(|| config.funders.get(account_id))
+    }
+
+    /// Whether one of the accounts funded the other, or both were funded by the
+    /// same account
+    pub fn are_related(&self, config: &WashTradeConfig, a: &AccountId, b: &AccountId) -> bool {
+        if a == b {
+            return true;
+        }
+        let funder_a = self.funder(config, a);
+        let funder_b = self.funder(config, b);
+        funder_a == Some(b) || funder_b == Some(a) || (funder_a.is_some() && funder_a == funder_b)
+    }
+
+    /// Checks all trades of the transfer and remembers the sellers. Returns true
+    /// if any of the trades looks like a wash trade.
+    pub fn analyze(
+        &mut self,
+        config: &WashTradeConfig,
+        contract_id: &AccountId,
+        transfer: &ExtendedNftTransferEvent,
+        block_timestamp_nanosec: u128,
+    ) -> bool {
+        let old_owner_id = &transfer.event.old_owner_id;
+        let new_owner_id = &transfer.event.new_owner_id;
+        let mut suspected = false;
+        for (token_id, trade) in transfer
+            .event
+            .token_ids
+            .iter()
+            .zip(&transfer.trade.token_trades)
+        {
+            let Some(trade) = trade else {
+                continue;
+            };
+            let owners = self
+                .previous_owners
+                .entry((contract_id.clone(), token_id.clone()))
+                .or_default();
+            let returned = owners.iter().any(|(owner_id, timestamp)| {
+                owner_id == new_owner_id
+                    && block_timestamp_nanosec.saturating_sub(*timestamp)
+                        < config.return_window.as_nanos()
+            });
+            owners.push((old_owner_id.clone(), block_timestamp_nanosec));
+
+            if returned {
+                log::debug!("Token {token_id} of {contract_id} returned to {new_owner_id}");
+                suspected = true;
+            }
+            if self.are_related(config, old_owner_id, new_owner_id)
+                || self.are_related(config, old_owner_id, &trade.buyer_id)
+            {
+                log::debug!("Seller {old_owner_id} and buyer {new_owner_id} are related");
+                suspected = true;
+            }
+            if self.payout_share_to_seller(config, old_owner_id, trade)
+                >= config.min_payout_share_to_seller
+            {
+                log::debug!(
+                    "Royalties for token {token_id} of {contract_id} go back to {old_owner_id}"
+                );
+                suspected = true;
+            }
+        }
+        suspected
+    }
+
+    /// 0.0 if the trade pays no royalties, since then all of the payout goes to
+    /// the seller in any trade
+    fn payout_share_to_seller(
+        &self,
+        config: &WashTradeConfig,
+        seller_id: &AccountId,
+        trade: &TokenTrade,
+    ) -> f64 {
+        let Some(payout) = &trade.payout else {
+            return 0.0;
+        };
+        let total = payout.total();
+        if payout.royalties.values().sum::<Balance>() == 0 || total == 0 {
+            return 0.0;
+        }
+        let royalties_to_seller: Balance = payout
+            .royalties
+            .iter()
+            .filter(|(account_id, _)| self.are_related(config, account_id, seller_id))
+            .map(|(_, amount)| amount)
+            .sum();
+        (payout.seller_proceeds + royalties_to_seller) as f64 / total as f64
+    }
+
+    pub fn end_block(&mut self, config: &WashTradeConfig, block_timestamp_nanosec: u128) {
+        if block_timestamp_nanosec.saturating_sub(self.last_pruned_nanosec)
+            < PRUNE_INTERVAL.as_nanos()
+        {
+            return;
+        }
+        self.last_pruned_nanosec = block_timestamp_nanosec;
+        let window = config.return_window.as_nanos();
+        self.previous_owners.retain(|_, owners| {
+            owners.retain(|(_, timestamp)| {
+                block_timestamp_nanosec.saturating_sub(*timestamp) < window
+            });
+            !owners.is_empty()
+        });
+    }
+}